@@ -0,0 +1,139 @@
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose
+};
+use time::{Duration, OffsetDateTime};
+use crate::config::ServerConfig;
+
+/// Where the generated files are written by default
+pub const DEFAULT_DIRECTORY: &str = "certs";
+
+/// Names every development certificate is valid for
+const LOCAL_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+/// Files written by `generate`
+pub struct Generated {
+    pub ca: String,
+    /// Whether the CA was created now, an existing one has to be trusted only once
+    pub new_ca: bool,
+    pub cert: String,
+    pub key: String,
+    pub names: Vec<String>,
+}
+
+impl Generated {
+    pub fn describe(&self) -> String {
+        let mut lines = Vec::new();
+        if self.new_ca {
+            lines.push(format!("Created the local CA {}, add it to the trusted certificates of your browser or system", self.ca));
+        }
+        lines.push(format!("Created {} for {}, signed by {}", self.cert, self.names.join(", "), self.ca));
+        lines.join("\n")
+    }
+}
+
+/// The host names of the config the development certificate should be valid for: the
+/// hosts of the listeners and of `[[server.tls.certs]]`, besides the local addresses
+pub fn configured_names(config: &ServerConfig) -> Vec<String> {
+    let mut names = LOCAL_NAMES.map(String::from).to_vec();
+    let hosts = config.server.listeners().into_iter()
+        .filter(|listener| !listener.address.starts_with("unix:"))
+        .filter_map(|listener| listener.address.rsplit_once(':')
+            .map(|(host, _)| host.trim_start_matches('[').trim_end_matches(']').to_string()));
+    let certs = config.server.tls.iter()
+        .flat_map(|tls| &tls.certs)
+        .flat_map(|cert| cert.names.iter().cloned());
+    for name in hosts.chain(certs) {
+        // wildcard listeners are reached through one of the local addresses
+        let unspecified = name.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified());
+        if !name.is_empty() && !unspecified && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// Write a certificate for `names` to `directory`, signed by the local CA of the directory
+/// which is created first if there is none
+pub fn generate(directory: &str, names: Vec<String>) -> Result<Generated, String> {
+    let directory = Path::new(directory);
+    std::fs::create_dir_all(directory)
+        .map_err(|err| format!("could not create `{}`: {err}", directory.display()))?;
+    let path = |file: &str| directory.join(file).to_string_lossy().into_owned();
+    let (ca, ca_key) = (path("ca.pem"), path("ca-key.pem"));
+    let (cert, key) = (path("gateserver.pem"), path("gateserver-key.pem"));
+
+    let new_ca = !Path::new(&ca).exists();
+    let (ca_cert, ca_key_pair) = if new_ca {
+        let key_pair = KeyPair::generate().map_err(|err| format!("could not generate the CA key: {err}"))?;
+        let ca_cert = ca_params().self_signed(&key_pair)
+            .map_err(|err| format!("could not create the CA certificate: {err}"))?;
+        write_file(&ca, &ca_cert.pem(), false)?;
+        write_file(&ca_key, &key_pair.serialize_pem(), true)?;
+        (ca_cert, key_pair)
+    } else {
+        let read = |file: &str| std::fs::read_to_string(file).map_err(|err| format!("could not read `{file}`: {err}"));
+        let key_pair = KeyPair::from_pem(&read(&ca_key)?)
+            .map_err(|err| format!("`{ca_key}` is not a supported private key: {err}"))?;
+        let ca_cert = CertificateParams::from_ca_cert_pem(&read(&ca)?)
+            .and_then(|params| params.self_signed(&key_pair))
+            .map_err(|err| format!("could not use the CA certificate `{ca}`: {err}"))?;
+        (ca_cert, key_pair)
+    };
+
+    let key_pair = KeyPair::generate().map_err(|err| format!("could not generate the key: {err}"))?;
+    let leaf = leaf_params(names.clone())?
+        .signed_by(&key_pair, &ca_cert, &ca_key_pair)
+        .map_err(|err| format!("could not sign the certificate: {err}"))?;
+    // the chain includes the CA, so clients that trust it can verify the certificate
+    write_file(&cert, &format!("{}{}", leaf.pem(), ca_cert.pem()), false)?;
+    write_file(&key, &key_pair.serialize_pem(), true)?;
+    Ok(Generated { ca, new_ca, cert, key, names })
+}
+
+fn ca_params() -> CertificateParams {
+    let mut params = CertificateParams::default();
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, "gateserver development CA");
+    params.distinguished_name = name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + Duration::days(10 * 365);
+    params
+}
+
+fn leaf_params(names: Vec<String>) -> Result<CertificateParams, String> {
+    let mut params = CertificateParams::new(names.clone())
+        .map_err(|err| format!("invalid host name in {}: {err}", names.join(", ")))?;
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, names.first().map_or("localhost", String::as_str));
+    params.distinguished_name = name;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.use_authority_key_identifier_extension = true;
+    // browsers refuse server certificates valid for more than about a year
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + Duration::days(365);
+    Ok(params)
+}
+
+fn write_file(path: &str, contents: &str, private: bool) -> Result<(), String> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    options.open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(|err| format!("could not write `{path}`: {err}"))
+}
@@ -0,0 +1,75 @@
+use clap::{Parser, Subcommand};
+use crate::{cert, config::{self, Overrides}};
+
+/// A flexible server with a web server, WebSocket, TCP and reverse proxies
+#[derive(Parser)]
+#[command(version, author, about)]
+pub struct Cli {
+    /// Path of the config file, it is created with the defaults if it does not exist
+    #[arg(long, global = true, value_name = "PATH", default_value = config::DEFAULT_CONFIG_FILE)]
+    pub config: String,
+
+    /// Listen on this port instead of `server.port`, ignored when `server.listeners` is set
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Use this log filter instead of `server.log_level`
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+
+    /// Run without the interactive console
+    #[arg(long)]
+    pub no_repl: bool,
+
+    /// Print the default config file and exit
+    #[arg(long)]
+    pub print_default_config: bool,
+
+    /// Upgrade an outdated config file to the current version before starting,
+    /// the original file is kept as `<PATH>.bak`
+    #[arg(long)]
+    pub migrate_config: bool,
+
+    /// Validate the config file and exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Print the routes that would be served and exit
+    #[arg(long)]
+    pub dry_run: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Manage the TLS certificates
+    Cert {
+        #[command(subcommand)]
+        action: CertCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CertCommand {
+    /// Create a local CA and a certificate signed by it for development,
+    /// and serve HTTPS with the certificate
+    Generate {
+        /// Host names of the certificate, the local addresses and the hosts of the config by default
+        names: Vec<String>,
+
+        /// Directory of the generated files, the CA found there is reused
+        #[arg(long, value_name = "PATH", default_value = cert::DEFAULT_DIRECTORY)]
+        dir: String,
+    },
+}
+
+impl Cli {
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            port: self.port.map(u32::from),
+            log_level: self.log_level.clone(),
+        }
+    }
+}
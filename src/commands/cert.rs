@@ -0,0 +1,25 @@
+use crate::{cert, config::{self, SERVER_CONFIG}};
+use crate::ServerContext;
+use super::ArgSlice;
+
+pub async fn generate(
+    args: ArgSlice<'_>,
+    _state: &ServerContext,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let names = if args.is_empty() {
+        cert::configured_names(&SERVER_CONFIG.read().unwrap())
+    } else {
+        args.iter().map(|name| name.to_string()).collect()
+    };
+    let generated = cert::generate(cert::DEFAULT_DIRECTORY, names)?;
+    // a certificate already in use is reloaded by the certificate watcher
+    let in_use = SERVER_CONFIG.read().unwrap().server.tls.as_ref()
+        .is_some_and(|tls| tls.cert.as_ref() == Some(&generated.cert) && tls.key.as_ref() == Some(&generated.key));
+    config::use_certificate(&generated.cert, &generated.key);
+    let next = if in_use {
+        "The running server uses it for new connections"
+    } else {
+        "Run `config save` to keep it in the configuration file and restart to serve HTTPS with it"
+    };
+    Ok(format!("{}\n{next}", generated.describe()))
+}
@@ -1,4 +1,4 @@
-use crate::config::{SERVER_CONFIG, CONFIG_FILE};
+use crate::config::{self, SERVER_CONFIG, ProxyKind, Source};
 use crate::ServerContext;
 use super::ArgSlice;
 
@@ -6,36 +6,55 @@ pub async fn timeout(
     args: ArgSlice<'_>,
     _state: &ServerContext,
 ) -> Result<String, Box<dyn std::error::Error>> {
-    const USAGE: &str = "Usage: config timeout [websocket_proxy|tcp_proxy] [timeout]";
+    const USAGE: &str = "Usage: config timeout [websocket_proxy|tcp_proxy] [name] [timeout]";
 
-    if args.len() != 2 {
+    if args.len() != 3 {
         return Ok(USAGE.to_string());
     }
 
     let service = args[0];
-    let timeout = args[1].parse::<u64>()?;
-
-    match service {
-        "websocket_proxy" => {
-            if let Some(ref mut config) = SERVER_CONFIG.write().unwrap().websocket_proxy {
-                config.timeout = timeout;
-                tracing::info!("Timeout for WebSocket proxy had been set to {}", config.timeout);
-                Ok(String::from("Successfully updated the timeout config for WebSocket proxy"))
-            } else {
-                Err("Could not find configuration for websocket_proxy")?
-            }
-        },
-        "tcp_proxy" => {
-            if let Some(ref mut config) = SERVER_CONFIG.write().unwrap().tcp_proxy {
-                config.timeout = timeout;
-                tracing::info!("Timeout for TCP proxy had been set to {}", config.timeout);
-                Ok(String::from("Successfully updated the timeout config for TCP proxy"))
-            } else {
-                Err("Could not find configuration for tcp_proxy")?
-            }
-        },
+    let name = args[1];
+    let timeout = args[2].parse::<u64>()?;
+
+    let kind = match ProxyKind::from_key(service) {
+        Some(kind @ (ProxyKind::WebSocket | ProxyKind::Tcp)) => kind,
         _ => Err("Only `websocket_proxy` and `tcp_proxy` allowed")?,
+    };
+    if let Some(config) = SERVER_CONFIG.write().unwrap().proxy_mut(kind, name) {
+        config.timeout = timeout;
+        config::mark_source(&format!("{service}.{name}.timeout"), Source::Console);
+        tracing::info!("Timeout for {service} '{name}' had been set to {}", config.timeout);
+        Ok(format!("Successfully updated the timeout config for {service} '{name}'"))
+    } else {
+        Err(format!("Could not find configuration for {service} '{name}'"))?
+    }
+}
+
+pub async fn get(
+    args: ArgSlice<'_>,
+    _state: &ServerContext,
+) -> Result<String, Box<dyn std::error::Error>> {
+    const USAGE: &str = "Usage: config get [path]";
+
+    if args.len() != 1 {
+        return Ok(USAGE.to_string());
+    }
+
+    Ok(config::describe(args[0])?)
+}
+
+pub async fn set(
+    args: ArgSlice<'_>,
+    _state: &ServerContext,
+) -> Result<String, Box<dyn std::error::Error>> {
+    const USAGE: &str = "Usage: config set [path] [value]";
+
+    if args.len() < 2 {
+        return Ok(USAGE.to_string());
     }
+
+    // values may contain spaces, e.g. a log filter or a quoted TOML array
+    Ok(crate::reload::set(args[0], &args[1..].join(" ")).await?)
 }
 
 pub async fn save(
@@ -44,17 +63,35 @@ pub async fn save(
 ) -> Result<String, Box<dyn std::error::Error>> {
     const USAGE: &str = "Usage: config save";
 
-    if args.len() != 0 {
+    if !args.is_empty() {
         return Ok(USAGE.to_string());
     }
 
-    match std::fs::write(CONFIG_FILE, toml::to_string(&*SERVER_CONFIG).unwrap()) {
-        Ok(_) => {
-            tracing::info!("The current configuration has been saved to {CONFIG_FILE}");
-            Ok(String::from("Successfully updated the configuration file"))
-        },
-        Err(err) => Err(err)?,
+    let mut lines = Vec::new();
+    for file in config::saved_files()?.iter().filter(|file| file.saved != file.running) {
+        if let Some(backup) = config::write_saved(file)? {
+            lines.push(format!("Migrated {} to version {}, the previous version was saved to {backup}", file.name, config::CONFIG_VERSION));
+        }
+        tracing::info!("The current configuration has been saved to {}", file.name);
+        lines.push(format!("Successfully updated {}", file.name));
+    }
+    if lines.is_empty() {
+        return Ok(String::from("The configuration files are up to date"));
     }
+    Ok(lines.join("\n"))
+}
+
+pub async fn diff(
+    args: ArgSlice<'_>,
+    _state: &ServerContext,
+) -> Result<String, Box<dyn std::error::Error>> {
+    const USAGE: &str = "Usage: config diff";
+
+    if !args.is_empty() {
+        return Ok(USAGE.to_string());
+    }
+
+    Ok(config::unsaved_changes()?.unwrap_or_else(|| String::from("The configuration files are up to date")))
 }
 
 pub async fn show(
@@ -63,17 +100,22 @@ pub async fn show(
 ) -> Result<String, Box<dyn std::error::Error>> {
     const USAGE: &str = "Usage: config show";
 
-    if args.len() != 0 {
+    if !args.is_empty() {
         return Ok(USAGE.to_string());
     }
 
-    let config = {
-        let config = SERVER_CONFIG.read().unwrap();
-        config.clone()
-    };
+    Ok(config::annotated())
+}
 
-    match toml::to_string(&config) {
-        Ok(result) => Ok(result),
-        Err(err) => Err(format!("Could not get current configuration: {}", err))?
+pub async fn reload(
+    args: ArgSlice<'_>,
+    _state: &ServerContext,
+) -> Result<String, Box<dyn std::error::Error>> {
+    const USAGE: &str = "Usage: config reload";
+
+    if !args.is_empty() {
+        return Ok(USAGE.to_string());
     }
+
+    Ok(crate::reload::reload().await?)
 }
@@ -1,12 +1,11 @@
+mod cert;
 mod config;
 mod net;
 
-use std::sync::Arc;
-use tokio::sync::Mutex;
 use std::io::Write;
 use rustyline_async::{Readline, SharedWriter, ReadlineEvent};
 use tracing_appender::non_blocking::WorkerGuard;
-use crate::ServerContext;
+use crate::{ServerContext, reload};
 
 type ArgSlice<'a> = &'a [&'a str];
 
@@ -48,29 +47,21 @@ macro_rules! commands {
     };
 }
 
-pub struct CommandManager {
-    context: Arc<Mutex<Option<Arc<ServerContext>>>>,
-}
+pub struct CommandManager;
 
 impl CommandManager {
     pub fn new() -> Self {
-        Self { context: Arc::new(Mutex::new(None)) }
-    }
-
-    pub async fn set_context(&mut self, new_context: Arc<ServerContext>) {
-        let mut context = self.context.lock().await;
-        *context = Some(new_context);
+        Self
     }
 
     pub fn run(&self, mut rl: Readline, mut out: SharedWriter, guard: WorkerGuard) {
-        let context = Arc::clone(&self.context);
         tokio::spawn(async move {
             loop {
                 match rl.readline().await {
                     Ok(ReadlineEvent::Line(line)) => {
-                        let context = context.lock().await;
-                        if let Some(context) = &*context {
-                            let str = Self::exec(context, &line).await;
+                        // the context is replaced on every config reload
+                        if let Some(context) = reload::current_context() {
+                            let str = Self::exec(&context, &line).await;
                             writeln!(&mut out, "{str}").unwrap();
                             rl.add_history_entry(line);
                         } else {
@@ -92,9 +83,15 @@ impl CommandManager {
     }
 
     commands! {
-        config::timeout "[websocket_proxy|tcp_proxy] [timeout]" "Set the service timeout";
+        config::timeout "[websocket_proxy|tcp_proxy] [name] [timeout]" "Set the service timeout";
+        config::get "[path]" "Show a value of the configuration, e.g. `tcp_proxy.default.timeout`";
+        config::set "[path] [value]" "Change a value of the configuration, applied right away unless it requires a restart";
         config::save "" "Save the current configuration to file";
+        config::diff "" "Show what `config save` would change in the configuration file";
+        config::reload "" "Reload the configuration file and apply the changes";
         config::show "" "Show the current configuration";
-        net::reconnect "[websocket_proxy|tcp_proxy]" "Reconnect service";
+        net::reconnect "[websocket_proxy|tcp_proxy] [name]" "Reconnect service";
+        net::status "" "Show the connection state of the websocket and TCP proxies and forwards";
+        cert::generate "[name ...]" "Create a development certificate signed by a local CA and use it for HTTPS";
     }
 }
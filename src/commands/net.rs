@@ -1,63 +1,118 @@
-use crate::config::SERVER_CONFIG;
+use crate::config::{SERVER_CONFIG, ProxyKind};
 use crate::ServerContext;
 use super::ArgSlice;
-use crate::utils::{create_websocket_stream, create_tcp_stream};
 
 pub async fn reconnect(
     args: ArgSlice<'_>,
     state: &ServerContext,
 ) -> Result<String, Box<dyn std::error::Error>> {
-    const USAGE: &str = "Usage: net reconnect [websocket_proxy|tcp_proxy]";
+    const USAGE: &str = "Usage: net reconnect [websocket_proxy|tcp_proxy] [name]";
 
-    if args.len() != 1 {
+    if args.len() != 2 {
         return Ok(USAGE.to_string());
     }
 
     let service = args[0];
+    let name = args[1];
 
     match service {
         "websocket_proxy" => {
             let config = {
                 let guard = SERVER_CONFIG.read().unwrap();
-                guard.websocket_proxy.clone()
+                guard.proxy(ProxyKind::WebSocket, name).cloned()
             };
-            if let (Some(config), Some(ws)) = (config, &state.ws_proxy) {
-                match create_websocket_stream(config.forward_to.clone()).await {
-                    Some(new_ws) => {
-                        let mut ws = ws.lock().await;
-                        *ws = new_ws;
-                        tracing::info!("Reconnected to Websocket server");
-                        Ok(String::from("Successfully reconnected to Websocket server"))
-                    },
-                    None => {
-                        Err("Failed to reconnect to Websocket server")?
-                    }
+            if let (Some(_), Some(multiplexer)) = (&config, state.ws_multiplexed.get(name)) {
+                if multiplexer.reconnect().await {
+                    tracing::info!("Reconnected to Websocket server '{name}'");
+                    Ok(format!("Successfully reconnected to Websocket server '{name}'"))
+                } else {
+                    Err(format!("Failed to reconnect to Websocket server '{name}'"))?
+                }
+            } else if let (Some(_), Some(pool)) = (config, state.ws_proxy.get(name)) {
+                if pool.recycle().await {
+                    tracing::info!("Reconnected to Websocket server '{name}'");
+                    Ok(format!("Successfully reconnected to Websocket server '{name}'"))
+                } else {
+                    Err(format!("Failed to reconnect to Websocket server '{name}'"))?
                 }
             } else {
-                Err("Could not find configuration or connection for websocket_proxy")?
+                Err(format!("Could not find configuration or connection for websocket_proxy '{name}'"))?
             }
         },
         "tcp_proxy" => {
             let config = {
                 let guard = SERVER_CONFIG.read().unwrap();
-                guard.tcp_proxy.clone()
+                guard.proxy(ProxyKind::Tcp, name).cloned()
             };
-            if let (Some(ref config), Some(tcp)) = (config, &state.tcp_proxy) {
-                match create_tcp_stream(config.forward_to.clone()).await {
-                    Some(new_tcp) => {
-                        let mut tcp = tcp.lock().await;
-                        *tcp = new_tcp;
-                        tracing::info!("Reconnected to TCP server");
-                        Ok(String::from("Successfully reconnected to TCP server"))
-                    },
-                    None => {
-                        Err("Failed to reconnect to TCP server")?
-                    }
+            if let (Some(_), Some(pool)) = (config, state.tcp_proxy.get(name)) {
+                if pool.recycle().await {
+                    tracing::info!("Reconnected to TCP server '{name}'");
+                    Ok(format!("Successfully reconnected to TCP server '{name}'"))
+                } else {
+                    Err(format!("Failed to reconnect to TCP server '{name}'"))?
                 }
             } else {
-                Err("Could not find configuration or connection for tcp_proxy")?
+                Err(format!("Could not find configuration or connection for tcp_proxy '{name}'"))?
             }
         },
         _ => Err("Only `websocket_proxy` and `tcp_proxy` allowed")?,
     }
 }
+
+pub async fn status(
+    args: ArgSlice<'_>,
+    state: &ServerContext,
+) -> Result<String, Box<dyn std::error::Error>> {
+    if !args.is_empty() {
+        return Ok(String::from("Usage: net status"));
+    }
+
+    let config = SERVER_CONFIG.read().unwrap().clone();
+    let mut lines = Vec::new();
+    for proxy in &config.websocket_proxy {
+        let line = if let Some(multiplexer) = state.ws_multiplexed.get(&proxy.name) {
+            format!("{}, {} requests waiting", multiplexer.supervisor().describe(), multiplexer.waiting())
+        } else if let Some(pool) = state.ws_proxy.get(&proxy.name) {
+            let (idle, in_use) = pool.size();
+            format!("{}, {idle} idle and {in_use} in use", pool.supervisor().describe())
+        } else {
+            continue;
+        };
+        lines.push(format!("websocket_proxy '{}' ({}): {line}", proxy.name, proxy.forward_to));
+    }
+    for proxy in &config.tcp_proxy {
+        if let Some(pool) = state.tcp_proxy.get(&proxy.name) {
+            let (idle, in_use) = pool.size();
+            lines.push(format!("tcp_proxy '{}' ({}): {}, {idle} idle and {in_use} in use",
+                proxy.name,
+                proxy.forward_to,
+                pool.supervisor().describe()));
+        }
+    }
+    for forward in &config.tcp_forward {
+        if let Some(listening) = state.tcp_forward.get(&forward.name) {
+            lines.push(format!("tcp_forward '{}' ({} -> {}): {}",
+                forward.name,
+                forward.listen,
+                forward.forward_to,
+                listening.stats().describe()));
+        } else {
+            lines.push(format!("tcp_forward '{}' ({} -> {}): not listening", forward.name, forward.listen, forward.forward_to));
+        }
+    }
+    for forward in &config.udp_forward {
+        let described = state.udp_forward.get(&forward.name).and_then(|listening| listening.describe());
+        let Some((summary, sessions)) = described else {
+            lines.push(format!("udp_forward '{}' ({} -> {}): not listening", forward.name, forward.listen, forward.forward_to));
+            continue;
+        };
+        lines.push(format!("udp_forward '{}' ({} -> {}): {summary}", forward.name, forward.listen, forward.forward_to));
+        for (client, session) in sessions {
+            lines.push(format!("  {client}: {session}"));
+        }
+    }
+    if lines.is_empty() {
+        return Ok(String::from("No websocket or TCP proxies or forwards are configured"));
+    }
+    Ok(lines.join("\n"))
+}
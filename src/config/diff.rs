@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use toml::Value;
+use super::ServerConfig;
+use super::sources::join;
+
+pub enum Change {
+    Added(String, Value),
+    Removed(String),
+    Modified(String, Value, Value),
+}
+
+impl Change {
+    pub fn path(&self) -> &str {
+        match self {
+            Change::Added(path, _) | Change::Removed(path) | Change::Modified(path, _, _) => path,
+        }
+    }
+
+    /// Listener and logging settings are bound at startup
+    pub fn requires_restart(&self) -> bool {
+        super::requires_restart(self.path())
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added(path, value) => write!(f, "+ {path} = {value}"),
+            Change::Removed(path) => write!(f, "- {path}"),
+            Change::Modified(path, old, new) => write!(f, "~ {path}: {old} -> {new}"),
+        }
+    }
+}
+
+/// Compare two configurations, proxy instances are matched by their names
+pub fn diff(old: &ServerConfig, new: &ServerConfig) -> Vec<Change> {
+    let mut changes = Vec::new();
+    if let (Ok(old), Ok(new)) = (Value::try_from(old), Value::try_from(new)) {
+        diff_value("", &old, &new, &mut changes);
+    }
+    changes
+}
+
+fn diff_value(path: &str, old: &Value, new: &Value, changes: &mut Vec<Change>) {
+    match (keyed(old), keyed(new)) {
+        (Some(old_items), Some(new_items)) => {
+            for (key, old_value) in &old_items {
+                let child = join(path, key);
+                match new_items.get(key) {
+                    Some(new_value) => diff_value(&child, old_value, new_value, changes),
+                    None => changes.push(Change::Removed(child)),
+                }
+            }
+            for (key, new_value) in &new_items {
+                if !old_items.contains_key(key) {
+                    changes.push(Change::Added(join(path, key), (*new_value).clone()));
+                }
+            }
+        }
+        _ if old != new => changes.push(Change::Modified(path.to_string(), old.clone(), new.clone())),
+        _ => {}
+    }
+}
+
+/// Tables are compared key by key, and so are arrays of named tables
+fn keyed(value: &Value) -> Option<BTreeMap<String, &Value>> {
+    match value {
+        Value::Table(table) => Some(table.iter().map(|(key, value)| (key.clone(), value)).collect()),
+        Value::Array(items) if !items.is_empty() => items.iter()
+            .map(|item| item.get("name").and_then(Value::as_str).map(|name| (name.to_string(), item)))
+            .collect(),
+        _ => None,
+    }
+}
@@ -0,0 +1,110 @@
+use similar::TextDiff;
+use toml::{Table, Value};
+use toml_edit::{DocumentMut, Item};
+use super::sources::{self, Source, Sources};
+
+/// Write `config` into the existing `document`, only the values that changed are touched so
+/// comments, ordering and formatting of the file survive. Values that are not in the file
+/// are only added when they were set from the console and `owns` accepts their path
+pub fn update(document: &mut DocumentMut, config: &Value, sources: &Sources, owns: &dyn Fn(&str) -> bool) {
+    if let Some(table) = config.as_table() {
+        update_table(document.as_table_mut(), "", table, sources, owns);
+    }
+}
+
+fn update_table(table: &mut toml_edit::Table, path: &str, config: &Table, sources: &Sources, owns: &dyn Fn(&str) -> bool) {
+    for (key, value) in config {
+        let path = sources::join(path, key);
+        match table.get_mut(key) {
+            Some(item) => update_item(item, &path, value, sources, owns),
+            None if set_from_console(&path, value, sources) && owns(&path) => {
+                let mut item = to_item(value);
+                if let Item::Table(table) = &mut item {
+                    table.decor_mut().set_prefix("\n");
+                }
+                table.insert(key, item);
+            }
+            None => {}
+        }
+    }
+}
+
+fn update_item(item: &mut Item, path: &str, value: &Value, sources: &Sources, owns: &dyn Fn(&str) -> bool) {
+    match (item, value) {
+        (Item::Table(table), Value::Table(config)) => update_table(table, path, config, sources, owns),
+        (Item::Value(toml_edit::Value::InlineTable(table)), Value::Table(config)) => {
+            let mut edited = table.clone().into_table();
+            update_table(&mut edited, path, config, sources, owns);
+            *table = edited.into_inline_table();
+        }
+        (Item::ArrayOfTables(instances), Value::Array(items)) if items.iter().all(Value::is_table) => {
+            for (index, config) in items.iter().enumerate() {
+                let key = sources::element_key(index, config);
+                let path = sources::join(path, &key);
+                let existing = instances.iter_mut()
+                    .enumerate()
+                    .find(|(index, table)| element_key(*index, table) == key);
+                match existing {
+                    Some((_, table)) => update_table(table, &path, config.as_table().unwrap(), sources, owns),
+                    None if set_from_console(&path, config, sources) && owns(&path) => instances.push(to_table(config)),
+                    None => {}
+                }
+            }
+        }
+        (Item::Value(current), value) if !same(current, value) => {
+            let decor = current.decor().clone();
+            if let Ok(mut new) = to_item(value).into_value() {
+                *new.decor_mut() = decor;
+                *current = new;
+            }
+        }
+        _ => {}
+    }
+}
+
+fn element_key(index: usize, table: &toml_edit::Table) -> String {
+    table.get("name")
+        .and_then(Item::as_str)
+        .map_or_else(|| index.to_string(), str::to_string)
+}
+
+fn set_from_console(path: &str, value: &Value, sources: &Sources) -> bool {
+    let mut console = false;
+    sources::for_each_leaf(path, value, &mut |path, _| {
+        console |= sources.get(path) == Some(&Source::Console);
+    });
+    console
+}
+
+fn same(current: &toml_edit::Value, value: &Value) -> bool {
+    let mut current = current.clone();
+    current.decor_mut().clear();
+    toml::from_str::<Table>(&format!("value = {current}"))
+        .is_ok_and(|table| table.get("value") == Some(value))
+}
+
+/// Format `value` like a freshly written config file would
+fn to_item(value: &Value) -> Item {
+    let mut wrapper = Table::new();
+    wrapper.insert(String::from("value"), value.clone());
+    let mut document = toml::to_string(&wrapper).unwrap()
+        .parse::<DocumentMut>()
+        .unwrap();
+    document.remove("value").unwrap()
+}
+
+fn to_table(value: &Value) -> toml_edit::Table {
+    match to_item(value) {
+        Item::Table(table) => table,
+        _ => toml_edit::Table::new(),
+    }
+}
+
+/// A line diff of `old` and `new` with `-` and `+` markers and a few lines of context
+pub fn unified_diff(old: &str, new: &str, file: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(2)
+        .header(file, &format!("{file} (running)"))
+        .to_string()
+}
@@ -0,0 +1,93 @@
+use std::path::Path;
+use toml::Value;
+use super::ProxyKind;
+use super::server_config::{Fragment, FORWARDS};
+use super::validate::{Issue, Locator, Segment, Severity};
+
+/// A config file or one of the files it includes, migrated to the current version
+pub struct ConfigFile {
+    pub name: String,
+    pub value: Value,
+}
+
+/// The files matched by the `include` patterns of the config file `main`, patterns are
+/// relative to the directory of `main` and the matches of each one are sorted by name
+pub fn resolve(main: &str, patterns: &[String]) -> (Vec<String>, Vec<Issue>) {
+    let directory = Path::new(main).parent().unwrap_or(Path::new(""));
+    let mut files = Vec::new();
+    let mut warnings = Vec::new();
+    for pattern in patterns {
+        let full = directory.join(pattern);
+        let paths = match glob::glob(&full.to_string_lossy()) {
+            Ok(paths) => paths,
+            Err(err) => {
+                warnings.push(Issue { key: String::from("include"), ..Issue::warning(main, format!("invalid pattern `{pattern}`: {err}")) });
+                continue;
+            }
+        };
+        let mut matched = paths.filter_map(Result::ok)
+            .filter(|path| path.is_file())
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        if matched.is_empty() {
+            warnings.push(Issue { key: String::from("include"), ..Issue::warning(main, format!("`{pattern}` does not match any file")) });
+        }
+        matched.sort();
+        for path in matched {
+            if path != main && !files.contains(&path) {
+                files.push(path);
+            }
+        }
+    }
+    (files, warnings)
+}
+
+/// Read an included file, it may only define proxies, forwards and the web service
+pub fn read(path: &str) -> Result<(ConfigFile, Locator<'static>), Issue> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|err| Issue::error(path, format!("could not read the included file: {err}")))?;
+    let locator = Locator::new(path, &source);
+    toml::from_str::<Fragment>(&source)
+        .map_err(|err| locator.parse_error(&err))?;
+    let mut document = super::parse_document(path, &source)?;
+    super::migrate::migrate(&mut document).map_err(|message| super::version_error(path, message))?;
+    let mut value: Value = toml::from_str(&document.to_string())
+        .map_err(|err| locator.parse_error(&err))?;
+    if let Some(table) = value.as_table_mut() {
+        table.remove("version");
+    }
+    Ok((ConfigFile { name: path.to_string(), value }, locator))
+}
+
+/// Add the content of `fragment` to `tree`, proxies are appended to the ones already defined
+/// while the web service may only be defined by one of the `files` read before
+pub fn merge(tree: &mut Value, fragment: &ConfigFile, locator: &Locator, files: &[ConfigFile]) -> Result<(), Issue> {
+    let conflict = fragment.value.as_table()
+        .into_iter()
+        .flat_map(|table| table.keys())
+        .filter(|key| ProxyKind::from_key(key).is_none() && !FORWARDS.contains(&key.as_str()))
+        .find_map(|key| files.iter().find(|file| file.value.get(key).is_some()));
+    if let Some(owner) = conflict {
+        return Err(locator.issue(Severity::Error, &[Segment::Key("web")],
+            format!("the web service is already defined in {}", owner.name)));
+    }
+    combine(tree, &fragment.value);
+    Ok(())
+}
+
+/// Append the proxies and forwards of `fragment` to `tree` and add everything else it defines
+pub fn combine(tree: &mut Value, fragment: &Value) {
+    let (Some(tree), Some(table)) = (tree.as_table_mut(), fragment.as_table()) else {
+        return;
+    };
+    for (key, value) in table {
+        if ProxyKind::from_key(key).is_some() || FORWARDS.contains(&key.as_str()) {
+            let instances = tree.entry(key.clone()).or_insert_with(|| Value::Array(Vec::new()));
+            if let (Value::Array(instances), Value::Array(added)) = (instances, value) {
+                instances.extend(added.iter().cloned());
+            }
+        } else {
+            tree.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use toml_edit::{ArrayOfTables, DocumentMut, Item, value};
+use super::ProxyKind;
+
+/// The version of the config file layout this build reads and writes
+pub const CONFIG_VERSION: i64 = 2;
+
+/// A step of the migration chain, it upgrades a document from `from` to `from + 1`
+struct Migration {
+    from: i64,
+    description: &'static str,
+    apply: fn(&mut DocumentMut),
+}
+
+const MIGRATIONS: [Migration; 1] = [
+    Migration {
+        from: 1,
+        description: "proxy sections became arrays of named instances",
+        apply: proxies_to_instances,
+    },
+];
+
+/// Files without a `version` key were written before versioning existed
+pub fn version_of(document: &DocumentMut) -> Result<i64, String> {
+    match document.get("version") {
+        None => Ok(1),
+        Some(item) => item.as_integer().ok_or_else(|| String::from("version must be an integer")),
+    }
+}
+
+/// Upgrade `document` in place to `CONFIG_VERSION`, returning what was changed;
+/// comments and layout of everything that is not migrated are kept
+pub fn migrate(document: &mut DocumentMut) -> Result<Vec<String>, String> {
+    let found = version_of(document)?;
+    if found > CONFIG_VERSION {
+        return Err(format!("config version {found} was written by a newer gateserver, this one supports up to {CONFIG_VERSION}"));
+    }
+    if found < 1 {
+        return Err(format!("unknown config version {found}"));
+    }
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|migration| migration.from >= found) {
+        (migration.apply)(document);
+        applied.push(format!("version {} -> {}: {}", migration.from, migration.from + 1, migration.description));
+    }
+    if !applied.is_empty() {
+        set_version(document, CONFIG_VERSION);
+    }
+    Ok(applied)
+}
+
+fn set_version(document: &mut DocumentMut, version: i64) {
+    let inserted = !document.contains_key("version");
+    // values of the root table are written before all tables, so it ends up at the top
+    document["version"] = value(version);
+    if inserted {
+        // separate it from the comments in front of the first table
+        let first = document.iter_mut()
+            .filter_map(|(_, item)| item.as_table_mut())
+            .min_by_key(|table| table.position().unwrap_or(usize::MAX));
+        if let Some(table) = first {
+            let prefix = table.decor().prefix().and_then(|prefix| prefix.as_str()).unwrap_or("").to_string();
+            table.decor_mut().set_prefix(format!("\n{prefix}"));
+        }
+    }
+}
+
+/// `[tcp_proxy]` becomes `[[tcp_proxy]]` with `name = "default"`
+fn proxies_to_instances(document: &mut DocumentMut) {
+    for kind in ProxyKind::ALL {
+        let Some(Item::Table(table)) = document.get(kind.key()) else {
+            continue;
+        };
+        let mut table = table.clone();
+        if !table.contains_key("name") {
+            table.insert("name", value("default"));
+            table.sort_values_by(|left, _, right, _| (left.get() != "name").cmp(&(right.get() != "name")));
+        }
+        let mut instances = ArrayOfTables::new();
+        instances.push(table);
+        document.insert(kind.key(), Item::ArrayOfTables(instances));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+
+    const V1: &str = include_str!("fixtures/v1.toml");
+    const V2: &str = include_str!("fixtures/v1_migrated.toml");
+
+    fn migrated(source: &str) -> Result<(Vec<String>, String), String> {
+        let mut document = source.parse::<DocumentMut>().unwrap();
+        let applied = migrate(&mut document)?;
+        Ok((applied, document.to_string()))
+    }
+
+    #[test]
+    fn migrates_version_1_keeping_comments() {
+        let (applied, migrated) = migrated(V1).unwrap();
+        assert_eq!(applied, ["version 1 -> 2: proxy sections became arrays of named instances"]);
+        assert_eq!(migrated, V2);
+        let config = toml::from_str::<ServerConfig>(&migrated).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.tcp_proxy[0].name, "default");
+        assert_eq!(config.websocket_proxy[0].name, "events");
+        assert_eq!(config.reverse_proxy.len(), 1);
+    }
+
+    #[test]
+    fn leaves_the_current_version_alone() {
+        assert_eq!(migrated(V2), Ok((Vec::new(), V2.to_string())));
+    }
+
+    #[test]
+    fn refuses_unknown_versions() {
+        for version in ["0", "3", "\"2\""] {
+            let source = format!("version = {version}\n{}", V1);
+            assert!(migrated(&source).is_err(), "version {version}");
+        }
+        let newer = format!("version = {}\n", CONFIG_VERSION + 1);
+        assert_eq!(migrated(&newer).unwrap_err(), format!("config version {} was written by a newer gateserver, this one supports up to {CONFIG_VERSION}", CONFIG_VERSION + 1));
+    }
+}
@@ -1,32 +1,433 @@
 mod server_config;
+mod diff;
+mod validate;
+mod sources;
+mod migrate;
+mod document;
+mod include;
+mod schema;
 
 use lazy_static::lazy_static;
-use std::sync::RwLock;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::{OnceLock, RwLock};
+use toml::Value;
+use toml_edit::DocumentMut;
 pub use server_config::ServerConfig;
+pub use server_config::ListenerConfig;
+pub use server_config::TlsConfig;
+pub use server_config::ClientAuthConfig;
 pub use server_config::ProxyConfig;
+pub use server_config::{EnvelopeConfig, EnvelopeFormat, FrameType, FramingCodec, FramingConfig, HandshakeConfig, ResponseEnd};
+pub use server_config::ProxyKind;
+pub use server_config::{ForwardConfig, UdpForwardConfig};
+pub use diff::diff;
+pub use validate::{Issue, ConfigError, Severity};
+pub use sources::{Source, Sources};
+pub use migrate::CONFIG_VERSION;
+use validate::{Locator, validate};
+use include::ConfigFile;
 
 const DEFAULT_CONFIG: &str = include_str!("./server.json");
-pub const CONFIG_FILE: &str = "server_config.toml";
+pub const DEFAULT_CONFIG_FILE: &str = "server_config.toml";
 
 lazy_static! {
-    pub static ref SERVER_CONFIG: RwLock<ServerConfig> = {
-        let default = serde_json::from_str(DEFAULT_CONFIG).unwrap();
-        RwLock::new(load_or_create_config(CONFIG_FILE, default))
+    // replaced by the content of the config file in `init_config`
+    pub static ref SERVER_CONFIG: RwLock<ServerConfig> = RwLock::new(default_config());
+    // where each value of `SERVER_CONFIG` comes from
+    pub static ref CONFIG_SOURCES: RwLock<Sources> = RwLock::new(Sources::new());
+}
+
+static CONFIG_FILE: OnceLock<String> = OnceLock::new();
+static OVERRIDES: OnceLock<Overrides> = OnceLock::new();
+
+/// Values given on the command line, they take precedence over env vars and the config file
+#[derive(Default)]
+pub struct Overrides {
+    pub port: Option<u32>,
+    pub log_level: Option<String>,
+}
+
+impl Overrides {
+    fn apply(&self, tree: &mut Value, sources: &mut Sources) {
+        let mut set = |path: &str, value: Value, flag| {
+            if let Some(current) = sources::find_mut(tree, path) {
+                *current = value;
+                sources.insert(path.to_string(), Source::Cli(flag));
+            }
+        };
+        if let Some(port) = self.port {
+            set("server.port", Value::Integer(port.into()), "--port");
+        }
+        if let Some(log_level) = &self.log_level {
+            set("server.log_level", Value::String(log_level.clone()), "--log-level");
+        }
+    }
+}
+
+/// Set the config file and the command line overrides, must be called before loading
+pub fn configure(path: String, overrides: Overrides) {
+    let _ = CONFIG_FILE.set(path);
+    let _ = OVERRIDES.set(overrides);
+}
+
+pub fn config_file() -> &'static str {
+    CONFIG_FILE.get().map_or(DEFAULT_CONFIG_FILE, String::as_str)
+}
+
+fn default_config() -> ServerConfig {
+    serde_json::from_str(DEFAULT_CONFIG).unwrap()
+}
+
+pub fn default_config_toml() -> String {
+    toml::to_string(&default_config()).unwrap()
+}
+
+/// A config ready to be used, with the sources of its values and its warnings
+pub struct Loaded {
+    pub config: ServerConfig,
+    pub sources: Sources,
+    pub warnings: Vec<Issue>,
+}
+
+/// The loaded config, or every problem that prevents the config from being used
+pub type LoadResult = Result<Loaded, ConfigError>;
+
+fn load_or_create_config(path: &str) -> LoadResult {
+    if Path::new(path).exists() {
+        return read_config(path);
+    }
+    if let Err(err) = std::fs::write(path, default_config_toml()) {
+        let warning = Issue::warning(path, format!("could not create the config file ({err}), running with the default configuration"));
+        let locator = Locator::new(path, "");
+        return layered(default_config(), &[], locator, vec![warning]);
+    }
+    read_config(path)
+}
+
+/// Read and validate a config file and the files it includes without touching the
+/// running configuration, env vars and command line options are layered on top of them
+pub fn read_config(path: &str) -> LoadResult {
+    let (config, main, mut locator, mut issues) = read_main(path)?;
+    let (included, warnings) = include::resolve(path, &config.include);
+    issues.extend(warnings);
+    if included.is_empty() {
+        return layered(config, &[main], locator, issues);
+    }
+
+    let mut tree = main.value.clone();
+    let mut files = vec![main];
+    let mut errors = Vec::new();
+    for path in included {
+        match include::read(&path) {
+            Ok((file, file_locator)) => {
+                if let Err(issue) = include::merge(&mut tree, &file, &file_locator, &files) {
+                    errors.push(issue);
+                }
+                locator.include(file_locator);
+                files.push(file);
+            }
+            Err(issue) => errors.push(issue),
+        }
+    }
+    if !errors.is_empty() {
+        errors.extend(issues);
+        return Err(ConfigError { issues: errors });
+    }
+    let config = tree.try_into()
+        .map_err(|err: toml::de::Error| Issue::error(path, err.message().trim()))?;
+    layered(config, &files, locator, issues)
+}
+
+/// Read the config file itself, older versions are migrated in memory
+fn read_main(path: &str) -> Result<(ServerConfig, ConfigFile, Locator<'static>, Vec<Issue>), ConfigError> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|err| Issue::error(path, format!("could not read the config file: {err}")))?;
+    let locator = Locator::new(path, &source);
+    let value: Value = toml::from_str(&source)
+        .map_err(|err| locator.parse_error(&err))?;
+    let mut document = parse_document(path, &source)?;
+    let version = migrate::version_of(&document).map_err(|message| version_error(path, message))?;
+    let migrations = migrate::migrate(&mut document).map_err(|message| version_error(path, message))?;
+    if migrations.is_empty() {
+        let config = toml::from_str(&source)
+            .map_err(|err| locator.parse_error(&err))?;
+        return Ok((config, ConfigFile { name: path.to_string(), value }, locator, Vec::new()));
+    }
+    // older files are only upgraded in memory, issues keep pointing at the lines of the original
+    let warning = Issue::warning(path, format!(
+        "config version {version} is outdated and was migrated in memory ({}), run with --migrate-config to update the file",
+        migrations.join("; ")));
+    let migrated = document.to_string();
+    let migrated_locator = Locator::new(path, &migrated);
+    let config = toml::from_str(&migrated)
+        .map_err(|err| migrated_locator.parse_error(&err))?;
+    let value = toml::from_str(&migrated)
+        .map_err(|err| migrated_locator.parse_error(&err))?;
+    Ok((config, ConfigFile { name: path.to_string(), value }, locator, vec![warning]))
+}
+
+pub(crate) fn parse_document(path: &str, source: &str) -> Result<DocumentMut, Issue> {
+    source.parse()
+        .map_err(|err: toml_edit::TomlError| Issue::error(path, err.message().trim()))
+}
+
+pub(crate) fn version_error(path: &str, message: String) -> Issue {
+    Issue { key: String::from("version"), ..Issue::error(path, message) }
+}
+
+/// Upgrade the config file to `CONFIG_VERSION` in place, the original is kept as `<path>.bak`.
+/// Returns the migrations that were applied, none if the file is up to date
+pub fn migrate_config_file(path: &str) -> Result<Vec<String>, ConfigError> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|err| Issue::error(path, format!("could not read the config file: {err}")))?;
+    let mut document = parse_document(path, &source)?;
+    let migrations = migrate::migrate(&mut document).map_err(|message| version_error(path, message))?;
+    if migrations.is_empty() {
+        return Ok(migrations);
+    }
+    back_up(path).map_err(|message| Issue::error(path, message))?;
+    std::fs::write(path, document.to_string())
+        .map_err(|err| Issue::error(path, format!("could not write the migrated config file: {err}")))?;
+    Ok(migrations)
+}
+
+/// Copy the config file to `<path>.bak` before it is written in a newer version
+fn back_up(path: &str) -> Result<String, String> {
+    let backup = format!("{path}.bak");
+    std::fs::copy(path, &backup)
+        .map_err(|err| format!("could not back up the config file to {backup}: {err}"))?;
+    Ok(backup)
+}
+
+/// Apply defaults < files < env < command line, then validate the result
+fn layered(config: ServerConfig, files: &[ConfigFile], locator: Locator, mut issues: Vec<Issue>) -> LoadResult {
+    let mut tree = Value::try_from(&config).unwrap();
+    let files = files.iter()
+        .map(|file| (file.name.as_str(), &file.value))
+        .collect::<Vec<_>>();
+    let mut sources = sources::track(&tree, &files);
+    sources::apply_env(&mut tree, &mut sources, &mut issues);
+    if let Some(overrides) = OVERRIDES.get() {
+        overrides.apply(&mut tree, &mut sources);
+    }
+    let config = match tree.try_into() {
+        Ok(config) => config,
+        Err(err) => {
+            issues.push(Issue::error("environment", err.to_string().trim()));
+            return Err(ConfigError { issues });
+        }
     };
+    let locator = locator.with_sources(&sources);
+    issues.extend(validate(&config, &locator));
+    if issues.iter().any(|issue| issue.severity == Severity::Error) {
+        return Err(ConfigError { issues });
+    }
+    Ok(Loaded { config, sources, warnings: issues })
 }
 
-fn load_or_create_config(path: &str, default: ServerConfig) -> ServerConfig {
-    std::fs::read_to_string(path).map_or_else(
-        | _ | {
-            std::fs::write(path, toml::to_string(&default).unwrap()).unwrap();
-            default
-        },
-        | data | {
-            toml::from_str(&data.as_str()).unwrap()
+/// The running config as TOML with the source of every value
+pub fn annotated() -> String {
+    let tree = Value::try_from(&*SERVER_CONFIG.read().unwrap()).unwrap();
+    sources::annotate(&tree, &CONFIG_SOURCES.read().unwrap())
+}
+
+/// The value at `path` of the running config with its source, every value below it for sections
+pub fn describe(path: &str) -> Result<String, String> {
+    let tree = Value::try_from(&*SERVER_CONFIG.read().unwrap()).unwrap();
+    let sources = CONFIG_SOURCES.read().unwrap();
+    let path = sources::resolve_path(&tree, path).map_or_else(|| path.to_string(), |(path, _)| path);
+    let value = sources::find(&tree, &path).ok_or_else(|| format!("`{path}` is not set in the configuration"))?;
+    let mut lines = Vec::new();
+    sources::for_each_leaf(&path, value, &mut |path, value| {
+        match sources.get(path) {
+            Some(source) => lines.push(format!("{path} = {value}  # {source}")),
+            None => lines.push(format!("{path} = {value}")),
         }
-    )
+    });
+    Ok(lines.join("\n"))
+}
+
+/// The running config with the value at `path` set to `raw`, checked like a config file.
+/// Returns the full path of the value, `path` may leave out the name of a single instance
+pub fn with_value(path: &str, raw: &str) -> Result<(String, Loaded), ConfigError> {
+    const CONSOLE: &str = "console";
+
+    let mut tree = Value::try_from(&*SERVER_CONFIG.read().unwrap()).unwrap();
+    let mut sources = CONFIG_SOURCES.read().unwrap().clone();
+    let (path, kind) = sources::resolve_path(&tree, path)
+        .ok_or_else(|| Issue { key: path.to_string(), ..Issue::error(CONSOLE, "not a value of the configuration") })?;
+    if path.ends_with(".name") && path.split('.').count() == 3 {
+        Err(Issue { key: path.clone(), ..Issue::error(CONSOLE, "instances can only be renamed in the config file") })?;
+    }
+    let value = sources::coerce(raw, kind)
+        .map_err(|message| Issue { key: path.clone(), ..Issue::error(CONSOLE, message) })?;
+    sources::set(&mut tree, &path, value);
+    sources.insert(path.clone(), Source::Console);
+    let config: ServerConfig = tree.try_into()
+        .map_err(|err: toml::de::Error| Issue { key: path.clone(), ..Issue::error(CONSOLE, err.message().trim()) })?;
+    let locator = Locator::new(CONSOLE, "").with_sources(&sources);
+    let issues = validate(&config, &locator);
+    if issues.iter().any(|issue| issue.severity == Severity::Error) {
+        return Err(ConfigError { issues });
+    }
+    Ok((path, Loaded { config, sources, warnings: issues }))
+}
+
+pub fn value_at(config: &ServerConfig, path: &str) -> Option<Value> {
+    sources::find(&Value::try_from(config).ok()?, path).cloned()
+}
+
+/// Whether a change to the value at `path` only takes effect after a restart
+pub fn requires_restart(path: &str) -> bool {
+    path == "server" || path.starts_with("server.")
+}
+
+/// Record that a value of the running config was changed
+pub fn mark_source(path: &str, source: Source) {
+    CONFIG_SOURCES.write().unwrap().insert(path.to_string(), source);
+}
+
+/// Serve HTTPS with `cert` and `key`, they are set like from the console so `config save` writes them
+pub fn use_certificate(cert: &str, key: &str) {
+    let tls = &mut SERVER_CONFIG.write().unwrap().server.tls;
+    let tls = tls.get_or_insert_with(TlsConfig::default);
+    tls.cert = Some(cert.to_string());
+    tls.key = Some(key.to_string());
+    mark_source("server.tls.cert", Source::Console);
+    mark_source("server.tls.key", Source::Console);
+}
+
+/// Write `cert` and `key` into `[server.tls]` of the config file and leave the rest of it as it is.
+/// A file of an older version is migrated first, returns where its previous version was backed up to
+pub fn save_certificate(cert: &str, key: &str) -> Result<Option<String>, String> {
+    let path = config_file();
+    let source = std::fs::read_to_string(path).map_err(|err| format!("could not read {path}: {err}"))?;
+    let mut document = parse_document(path, &source).map_err(|issue| issue.to_string())?;
+    let migrated = !migrate::migrate(&mut document)
+        .map_err(|message| format!("{path} can not be written: {message}"))?
+        .is_empty();
+    let tls = document.entry("server")
+        .or_insert_with(toml_edit::table)
+        .as_table_mut()
+        .ok_or_else(|| format!("[server] in {path} is not a table"))?
+        .entry("tls")
+        .or_insert_with(toml_edit::table);
+    tls["cert"] = toml_edit::value(cert);
+    tls["key"] = toml_edit::value(key);
+    let backup = if migrated { Some(back_up(path)?) } else { None };
+    std::fs::write(path, document.to_string()).map_err(|err| format!("could not write {path}: {err}"))?;
+    Ok(backup)
+}
+
+/// The running config as it should be written to the config file, values that
+/// come from env vars or the command line keep what the file had for them
+pub fn persistable(file: &Value) -> Value {
+    let mut tree = Value::try_from(&*SERVER_CONFIG.read().unwrap()).unwrap();
+    for (path, source) in CONFIG_SOURCES.read().unwrap().iter() {
+        if !matches!(source, Source::Env(_) | Source::Cli(_)) {
+            continue;
+        }
+        if let (Some(current), Some(saved)) = (sources::find_mut(&mut tree, path), sources::find(file, path)) {
+            *current = saved.clone();
+        }
+    }
+    tree
+}
+
+/// The files included by the running config
+pub fn included_files() -> Vec<String> {
+    include::resolve(config_file(), &SERVER_CONFIG.read().unwrap().include).0
+}
+
+/// A file of the config as it is on disk and as `config save` would write it
+pub struct SavedFile {
+    pub name: String,
+    pub saved: String,
+    pub running: String,
+    /// The file has an older version, it is backed up before it is written
+    pub migrated: bool,
+}
+
+/// Write `file` as `config save` would, returns where the previous version was backed up to
+pub fn write_saved(file: &SavedFile) -> Result<Option<String>, String> {
+    let backup = if file.migrated { Some(back_up(&file.name)?) } else { None };
+    std::fs::write(&file.name, &file.running)
+        .map_err(|err| format!("could not write {}: {err}", file.name))?;
+    Ok(backup)
+}
+
+/// The config file and the files it includes with the running config written into them in
+/// place, so their comments and layout are kept. Values are written to the file they come from.
+/// A config file of an older version is migrated, one that can not be migrated is not written
+pub fn saved_files() -> Result<Vec<SavedFile>, String> {
+    let path = config_file();
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(format!("{path} can not be written: {err}")),
+    };
+    if source.trim().is_empty() {
+        // nothing worth keeping, write the whole config
+        let running = toml::to_string(&persistable(&Value::Table(Default::default()))).unwrap();
+        return Ok(vec![SavedFile { name: path.to_string(), saved: source, running, migrated: false }]);
+    }
+    let mut main = parse_saved(path, &source)?;
+    let migrated = !migrate::migrate(&mut main)
+        .map_err(|message| format!("{path} can not be written: {message}"))?
+        .is_empty();
+    let mut documents = vec![(path.to_string(), source, main)];
+    for name in included_files() {
+        let source = match std::fs::read_to_string(&name) {
+            Ok(source) => source,
+            // its values are written to the config file
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(format!("{name} can not be written: {err}")),
+        };
+        let document = parse_saved(&name, &source)?;
+        documents.push((name, source, document));
+    }
+
+    let values = documents.iter()
+        .map(|(_, _, document)| toml::from_str(&document.to_string()).unwrap_or(Value::Table(Default::default())))
+        .collect::<Vec<Value>>();
+    let mut merged = values[0].clone();
+    for value in &values[1..] {
+        include::combine(&mut merged, value);
+    }
+    let tree = persistable(&merged);
+    let sources = CONFIG_SOURCES.read().unwrap();
+    // new values go to the config file unless an included file defines their section
+    let in_main = |path: &str| values[1..].iter().all(|value| sources::find(value, path).is_none());
+    Ok(documents.into_iter()
+        .enumerate()
+        .map(|(index, (name, saved, mut document))| {
+            let owns: &dyn Fn(&str) -> bool = if index == 0 { &in_main } else { &|_| false };
+            document::update(&mut document, &tree, &sources, owns);
+            SavedFile { name, saved, running: document.to_string(), migrated: migrated && index == 0 }
+        })
+        .collect())
+}
+
+/// A file that was edited since it was loaded may no longer parse, it is left alone then
+fn parse_saved(path: &str, source: &str) -> Result<DocumentMut, String> {
+    source.parse()
+        .map_err(|err: toml_edit::TomlError| format!("{path} can not be written: {}", err.message().trim()))
+}
+
+/// The changes `config save` would make to the config files, as a line diff
+pub fn unsaved_changes() -> Result<Option<String>, String> {
+    let diffs = saved_files()?.into_iter()
+        .filter(|file| file.saved != file.running)
+        .map(|file| document::unified_diff(&file.saved, &file.running, &file.name))
+        .collect::<Vec<_>>();
+    Ok((!diffs.is_empty()).then(|| diffs.join("\n")))
 }
 
-pub fn init_config() {
-    let _config = &*SERVER_CONFIG;
+/// Load the config file, creating it with the defaults on first run, and return its warnings
+pub fn init_config() -> Result<Vec<Issue>, ConfigError> {
+    let loaded = load_or_create_config(config_file())?;
+    *SERVER_CONFIG.write().unwrap() = loaded.config;
+    *CONFIG_SOURCES.write().unwrap() = loaded.sources;
+    Ok(loaded.warnings)
 }
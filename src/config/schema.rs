@@ -0,0 +1,377 @@
+use std::sync::OnceLock;
+use serde::de::{self, Deserialize, Deserializer, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::de::value::{Error, StrDeserializer};
+use super::ServerConfig;
+
+/// The shape of the config as its structs define it, including what is not set in the file
+#[derive(Debug, PartialEq)]
+pub enum Schema {
+    /// Neither a table nor an array of tables, with the TOML type of the value
+    Value(&'static str),
+    Table(Vec<(&'static str, Schema)>),
+    /// A table with keys of any name, like `handshake.headers`
+    Map(Box<Schema>),
+    /// An array of tables, its elements are addressed by `name` or index like in paths
+    Array(Box<Schema>),
+}
+
+impl Schema {
+    pub fn field(&self, key: &str) -> Option<&Schema> {
+        match self {
+            Schema::Table(fields) => fields.iter().find(|(field, _)| *field == key).map(|(_, schema)| schema),
+            _ => None,
+        }
+    }
+
+    /// Whether the elements of an array are instances, named by their `name`
+    pub fn is_named(&self) -> bool {
+        self.field("name").is_some()
+    }
+}
+
+/// The schema of `ServerConfig`
+pub fn config() -> &'static Schema {
+    static SCHEMA: OnceLock<Schema> = OnceLock::new();
+    SCHEMA.get_or_init(|| trace::<ServerConfig>().expect("every config struct can be traced"))
+}
+
+/// The schema of `T`, an error for types the tracer can not follow, like enums with data
+fn trace<'de, T: Deserialize<'de>>() -> Result<Schema, Error> {
+    let mut schema = None;
+    T::deserialize(Tracer { schema: &mut schema })?;
+    Ok(schema.unwrap_or(Schema::Value("any")))
+}
+
+/// A deserializer that records what the visitor asks for, and hands it a placeholder value
+struct Tracer<'a> {
+    schema: &'a mut Option<Schema>,
+}
+
+impl Tracer<'_> {
+    fn value(self, kind: &'static str) {
+        *self.schema = Some(Schema::Value(kind));
+    }
+}
+
+macro_rules! trace_integer {
+    ($($method:ident => $visit:ident),*) => {
+        $(fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            self.value("integer");
+            visitor.$visit(0)
+        })*
+    };
+}
+
+impl<'de> Deserializer<'de> for Tracer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.value("any");
+        visitor.visit_unit()
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.value("boolean");
+        visitor.visit_bool(false)
+    }
+
+    trace_integer!(
+        deserialize_i8 => visit_i64, deserialize_i16 => visit_i64, deserialize_i32 => visit_i64, deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u64, deserialize_u16 => visit_u64, deserialize_u32 => visit_u64, deserialize_u64 => visit_u64
+    );
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.value("float");
+        visitor.visit_f64(0.0)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.value("string");
+        visitor.visit_char(' ')
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.value("string");
+        visitor.visit_str("")
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.value("array");
+        visitor.visit_bytes(&[])
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.value("any");
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut element = None;
+        let value = visitor.visit_seq(Element { schema: Some(&mut element) })?;
+        *self.schema = Some(match element {
+            Some(table @ Schema::Table(_)) => Schema::Array(Box::new(table)),
+            _ => Schema::Value("array"),
+        });
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut entry = None;
+        let value = visitor.visit_map(Entries { keys: &[""], schemas: vec![None], at: 0, visited: None, entry: Some(&mut entry) })?;
+        *self.schema = Some(Schema::Map(Box::new(entry.unwrap_or(Schema::Value("any")))));
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        let mut entries = Entries { keys: fields, schemas: fields.iter().map(|_| None).collect(), at: 0, visited: None, entry: None };
+        let value = visitor.visit_map(&mut entries)?;
+        let fields = fields.iter()
+            .zip(entries.schemas)
+            .map(|(field, schema)| (*field, schema.unwrap_or(Schema::Value("any"))))
+            .collect();
+        *self.schema = Some(Schema::Table(fields));
+        Ok(value)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, variants: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        // the enums of the config are plain strings
+        self.value("string");
+        let variant: StrDeserializer<Error> = variants[0].into_deserializer();
+        visitor.visit_enum(variant)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+/// A sequence with a single element, traced into `schema`
+struct Element<'a> {
+    schema: Option<&'a mut Option<Schema>>,
+}
+
+impl<'de> SeqAccess<'de> for Element<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        match self.schema.take() {
+            Some(schema) => seed.deserialize(Tracer { schema }).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The fields of a struct, or a single entry of a map traced into `entry`
+struct Entries<'a> {
+    keys: &'static [&'static str],
+    schemas: Vec<Option<Schema>>,
+    at: usize,
+    visited: Option<usize>,
+    entry: Option<&'a mut Option<Schema>>,
+}
+
+impl<'de> MapAccess<'de> for Entries<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        let Some(key) = self.keys.get(self.at) else {
+            return Ok(None);
+        };
+        self.visited = Some(self.at);
+        self.at += 1;
+        let key: StrDeserializer<Error> = key.into_deserializer();
+        seed.deserialize(key).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let at = self.visited.take().ok_or_else(|| de::Error::custom("value without a key"))?;
+        match self.entry.as_deref_mut() {
+            Some(entry) => seed.deserialize(Tracer { schema: entry }),
+            None => seed.deserialize(Tracer { schema: &mut self.schemas[at] }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use serde::Deserialize;
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    #[allow(dead_code)]
+    enum Mode {
+        Fast,
+        Slow,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Instance {
+        name: String,
+        port: u16,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Listener {
+        address: String,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Wrapped(u64);
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Shapes {
+        flag: bool,
+        small: i8,
+        large: u64,
+        ratio: f32,
+        letter: char,
+        text: String,
+        mode: Mode,
+        wrapped: Wrapped,
+        #[serde(default)]
+        optional: Option<String>,
+        section: Option<Listener>,
+        instances: Vec<Instance>,
+        listeners: Vec<Listener>,
+        tags: Vec<String>,
+        optional_tags: Option<Vec<String>>,
+        headers: BTreeMap<String, String>,
+        groups: HashMap<String, Listener>,
+    }
+
+    fn field<'a>(schema: &'a Schema, path: &str) -> &'a Schema {
+        path.split('.').fold(schema, |schema, key| match schema {
+            Schema::Map(entry) => entry,
+            Schema::Array(element) => element,
+            _ => schema.field(key).unwrap_or_else(|| panic!("no field `{key}` in `{path}`")),
+        })
+    }
+
+    #[test]
+    fn traces_values_with_their_toml_type() {
+        let schema = trace::<Shapes>().unwrap();
+        for (path, kind) in [
+            ("flag", "boolean"),
+            ("small", "integer"),
+            ("large", "integer"),
+            ("ratio", "float"),
+            ("letter", "string"),
+            ("text", "string"),
+            ("mode", "string"),
+            ("wrapped", "integer"),
+            ("optional", "string"),
+            ("tags", "array"),
+            ("optional_tags", "array"),
+        ] {
+            assert_eq!(field(&schema, path), &Schema::Value(kind), "{path}");
+        }
+    }
+
+    #[test]
+    fn traces_tables_maps_and_arrays_of_tables() {
+        let schema = trace::<Shapes>().unwrap();
+        assert_eq!(field(&schema, "section"), &Schema::Table(vec![("address", Schema::Value("string"))]));
+        assert_eq!(field(&schema, "headers"), &Schema::Map(Box::new(Schema::Value("string"))));
+        assert_eq!(field(&schema, "groups.any.address"), &Schema::Value("string"));
+
+        let Schema::Array(instance) = field(&schema, "instances") else { panic!("instances are not an array") };
+        assert!(instance.is_named());
+        assert_eq!(field(instance, "port"), &Schema::Value("integer"));
+        let Schema::Array(listener) = field(&schema, "listeners") else { panic!("listeners are not an array") };
+        assert!(!listener.is_named());
+    }
+
+    #[test]
+    fn rejects_enums_with_data() {
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        enum Backend {
+            Address(String),
+            Unix { path: String },
+        }
+
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct WithData {
+            backend: Backend,
+        }
+
+        assert!(trace::<WithData>().is_err());
+    }
+
+    /// A field the tracer can not type is set as any TOML value, so env vars and `config set`
+    /// would skip the type check. Every field of the config must be typed
+    #[test]
+    fn config_has_no_untyped_values() {
+        fn untyped(schema: &Schema, path: &str, found: &mut Vec<String>) {
+            match schema {
+                Schema::Value("any") => found.push(path.to_string()),
+                Schema::Value(_) => {}
+                Schema::Table(fields) => {
+                    for (key, child) in fields {
+                        untyped(child, &format!("{path}.{key}"), found);
+                    }
+                }
+                Schema::Map(entry) | Schema::Array(entry) => untyped(entry, &format!("{path}.*"), found),
+            }
+        }
+
+        let mut found = Vec::new();
+        untyped(config(), "", &mut found);
+        assert!(found.is_empty(), "untyped config values: {found:?}");
+    }
+
+    #[test]
+    fn traces_the_config() {
+        let schema = config();
+        assert_eq!(field(schema, "server.port"), &Schema::Value("integer"));
+        assert_eq!(field(schema, "server.tls.cert"), &Schema::Value("string"));
+        assert_eq!(field(schema, "server.listeners.0.services"), &Schema::Value("array"));
+        assert_eq!(field(schema, "tcp_proxy.devices.handshake.headers.origin"), &Schema::Value("string"));
+        assert_eq!(field(schema, "tcp_proxy.devices.framing.codec"), &Schema::Value("string"));
+        assert!(matches!(field(schema, "tcp_proxy"), Schema::Array(proxy) if proxy.is_named()));
+        assert!(matches!(field(schema, "server.listeners"), Schema::Array(listener) if !listener.is_named()));
+    }
+}
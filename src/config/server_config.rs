@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -6,6 +7,137 @@ pub struct BaseConfig {
     pub port: u32,
     pub file_log: bool,
     pub log_level: String,
+    #[serde(default)]
+    pub watch_config: bool,
+    /// Replace `host:port` with these listeners when set
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<ListenerConfig>,
+    /// Serve HTTPS on the TCP listeners when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, used for clients whose SNI matches none of `certs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<String>,
+    /// PEM file with the private key of `cert`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Certificates picked by the server name the client asks for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub certs: Vec<CertConfig>,
+    /// Address of a plain HTTP listener that redirects every request to HTTPS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_http: Option<String>,
+    /// Ask clients for a certificate signed by a trusted CA
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct ClientAuthConfig {
+    /// PEM bundle of the CAs client certificates must be signed by
+    pub ca: String,
+    /// Paths that require a verified client certificate, every path when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paths: Option<Vec<String>>,
+    /// Request header carrying the subject of the client certificate to reverse proxy backends
+    #[serde(default = "ClientAuthConfig::default_header")]
+    pub header: String,
+    /// Field added to JSON object bodies sent to websocket and TCP proxy backends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+}
+
+impl ClientAuthConfig {
+    fn default_header() -> String {
+        String::from("X-Client-Subject")
+    }
+
+    /// Whether requests to `path` must come with a verified client certificate
+    pub fn requires(&self, path: &str) -> bool {
+        self.paths.as_ref().is_none_or(|paths| paths.iter().any(|prefix| {
+            let prefix = prefix.trim_end_matches('/');
+            path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        }))
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct CertConfig {
+    /// Server names served with this certificate, `*.example.com` matches one label
+    pub names: Vec<String>,
+    pub cert: String,
+    pub key: String,
+}
+
+impl TlsConfig {
+    /// Every certificate and key file, to watch them for changes
+    pub fn files(&self) -> Vec<&str> {
+        let mut files = self.cert.iter().chain(&self.key).map(String::as_str).collect::<Vec<_>>();
+        for cert in &self.certs {
+            files.push(&cert.cert);
+            files.push(&cert.key);
+        }
+        files
+    }
+}
+
+/// Services that can be mounted on a listener, proxies also by instance as `tcp_proxy.<name>`
+pub const SERVICES: [&str; 5] = ["websocket_proxy", "tcp_proxy", "reverse_proxy", "web", "api"];
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct ListenerConfig {
+    /// `host:port`, `[::]:port` or `unix:/path/to.sock`
+    pub address: String,
+    /// Services mounted on this listener, all of them when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub services: Option<Vec<String>>,
+    /// Serve plain HTTP on this listener even if `[server.tls]` is set
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub plain: bool,
+}
+
+impl ListenerConfig {
+    /// Whether the service `kind`, or its instance `name`, is mounted on this listener
+    pub fn serves(&self, kind: &str, name: Option<&str>) -> bool {
+        match &self.services {
+            None => true,
+            Some(services) => services.iter().any(|service| {
+                service == kind || name.is_some_and(|name| *service == format!("{kind}.{name}"))
+            }),
+        }
+    }
+}
+
+impl BaseConfig {
+    /// The listeners to bind, a single one on `host:port` serving everything if none are set
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        let address = if self.host.contains(':') && !self.host.starts_with('[') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        };
+        vec![ListenerConfig { address, services: None, plain: false }]
+    }
+
+    /// Whether `listener` serves HTTPS, Unix sockets are always plain
+    pub fn is_tls(&self, listener: &ListenerConfig) -> bool {
+        self.tls.is_some() && !listener.plain && !listener.address.starts_with("unix:")
+    }
+
+    /// The port HTTP requests are redirected to, the one of the first HTTPS listener
+    pub fn https_port(&self) -> Option<u16> {
+        self.listeners().iter()
+            .find(|listener| self.is_tls(listener))
+            .and_then(|listener| listener.address.rsplit_once(':'))
+            .and_then(|(_, port)| port.parse().ok())
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -17,16 +149,378 @@ pub struct WebConfig {
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ProxyConfig {
+    pub name: String,
     pub path: String,
     pub forward_to: String,
     pub timeout: u64,
+    /// Relay WebSocket connections opened on `path` to their own connection to `forward_to`,
+    /// only for websocket proxies
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub passthrough: bool,
+    /// Connections to `forward_to` kept open, only for websocket and TCP proxies
+    #[serde(default = "ProxyConfig::default_connections", skip_serializing_if = "ProxyConfig::is_default_connections")]
+    pub min_connections: usize,
+    /// Requests sent to `forward_to` at the same time, each on its own connection
+    #[serde(default = "ProxyConfig::default_connections", skip_serializing_if = "ProxyConfig::is_default_connections")]
+    pub max_connections: usize,
+    /// Milliseconds after which unused connections above `min_connections` are closed, 0 keeps them
+    #[serde(default, skip_serializing_if = "ProxyConfig::is_zero")]
+    pub idle_timeout: u64,
+    /// Milliseconds between WebSocket pings or TCP keepalive probes on idle connections, 0 disables them
+    #[serde(default = "ProxyConfig::default_heartbeat", skip_serializing_if = "ProxyConfig::is_default_heartbeat")]
+    pub heartbeat: u64,
+    /// Upper limit in milliseconds of the delay between attempts to reconnect to `forward_to`
+    #[serde(default = "ProxyConfig::default_max_backoff", skip_serializing_if = "ProxyConfig::is_default_max_backoff")]
+    pub max_backoff: u64,
+    /// WebSocket message type of the requests sent to `forward_to`, only for websocket proxies
+    #[serde(default, skip_serializing_if = "FrameType::is_binary")]
+    pub frame: FrameType,
+    /// Content-Type of the responses: a fixed type, `sniff` to guess it from the response, or
+    /// `message` to derive it from the WebSocket message type
+    #[serde(default = "ProxyConfig::default_content_type", skip_serializing_if = "ProxyConfig::is_default_content_type")]
+    pub content_type: String,
+    /// Tag requests with an ID so responses can arrive in any order, only for websocket proxies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope: Option<EnvelopeConfig>,
+    /// Stream the messages the backend sends on its own to HTTP clients, only for websocket and TCP proxies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub events: Option<EventsConfig>,
+    /// Headers and subprotocols of the handshake with `forward_to`, only for websocket proxies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handshake: Option<HandshakeConfig>,
+    /// How to tell when a response of several messages or reads is complete, only for websocket
+    /// and TCP proxies. Responses are the first message or read by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response: Option<ResponseConfig>,
+    /// How requests and responses are delimited on the byte stream, only for TCP proxies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub framing: Option<FramingConfig>,
+}
+
+impl ProxyConfig {
+    fn default_connections() -> usize {
+        1
+    }
+
+    fn is_default_connections(connections: &usize) -> bool {
+        *connections == 1
+    }
+
+    fn is_zero(value: &u64) -> bool {
+        *value == 0
+    }
+
+    fn is_zero_count(value: &usize) -> bool {
+        *value == 0
+    }
+
+    fn default_heartbeat() -> u64 {
+        10000
+    }
+
+    fn is_default_heartbeat(heartbeat: &u64) -> bool {
+        *heartbeat == Self::default_heartbeat()
+    }
+
+    fn default_max_backoff() -> u64 {
+        30000
+    }
+
+    fn is_default_max_backoff(max_backoff: &u64) -> bool {
+        *max_backoff == Self::default_max_backoff()
+    }
+
+    fn default_content_type() -> String {
+        String::from("application/json")
+    }
+
+    fn is_default_content_type(content_type: &String) -> bool {
+        *content_type == Self::default_content_type()
+    }
+
+    /// Where the events of the backend are streamed, if they are
+    pub fn events_path(&self) -> Option<String> {
+        let events = self.events.as_ref()?;
+        Some(events.path.clone().unwrap_or_else(|| format!("{}/events", self.path.trim_end_matches('/'))))
+    }
+
+    /// Whether the pool settings differ from a single connection kept forever
+    pub fn is_pooled(&self) -> bool {
+        self.min_connections != 1 || self.max_connections != 1 || self.idle_timeout != 0
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameType {
+    #[default]
+    Binary,
+    Text,
+    /// Text for textual request Content-Types like JSON, binary for everything else
+    Auto,
+}
+
+impl FrameType {
+    fn is_binary(&self) -> bool {
+        *self == FrameType::Binary
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvelopeFormat {
+    /// The ID is a number in a field of the JSON object of the message
+    Json,
+    /// The ID is a big endian `u32` in front of the message
+    Binary,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct EnvelopeConfig {
+    pub format: EnvelopeFormat,
+    /// Field of the ID in the `json` format
+    #[serde(default = "EnvelopeConfig::default_field")]
+    pub field: String,
+}
+
+impl EnvelopeConfig {
+    fn default_field() -> String {
+        String::from("id")
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq, Default)]
+pub struct HandshakeConfig {
+    /// Sent on every connection to the backend, like `Authorization` or `Origin`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Offered to the backend in `Sec-WebSocket-Protocol`, in order of preference
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protocols: Vec<String>,
+    /// Headers of the client request copied to the dedicated backend connection of a passthrough
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forward_headers: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseEnd {
+    /// After `count` messages
+    Count,
+    /// At the message or bytes of `terminator`, which are not part of the response
+    Terminator,
+    /// When the backend sent nothing for `idle` milliseconds
+    Idle,
+    /// Once the response is a complete JSON document
+    Json,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct ResponseConfig {
+    pub until: ResponseEnd,
+    #[serde(default, skip_serializing_if = "ProxyConfig::is_zero_count")]
+    pub count: usize,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub terminator: String,
+    #[serde(default, skip_serializing_if = "ProxyConfig::is_zero")]
+    pub idle: u64,
+    /// Send the response to the client in chunks as it arrives, instead of as one body
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum FramingCodec {
+    /// Every frame ends with a line feed, a carriage return before it is removed from responses
+    Lines,
+    /// A big endian `u16` length in front of every frame
+    U16be,
+    /// A little endian `u16` length in front of every frame
+    U16le,
+    /// A big endian `u32` length in front of every frame
+    U32be,
+    /// A little endian `u32` length in front of every frame
+    U32le,
+    /// Every frame has `size` bytes
+    Fixed,
+    /// Every frame ends with `delimiter`
+    Delimiter,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct FramingConfig {
+    pub codec: FramingCodec,
+    #[serde(default, skip_serializing_if = "ProxyConfig::is_zero_count")]
+    pub size: usize,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub delimiter: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct EventsConfig {
+    /// `events` below the path of the proxy by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Milliseconds between comments that keep idle streams open, 0 sends none
+    #[serde(default = "EventsConfig::default_keepalive")]
+    pub keepalive: u64,
+}
+
+impl EventsConfig {
+    fn default_keepalive() -> u64 {
+        15000
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ServerConfig {
+    /// Layout version of the config file, older files are migrated when read
+    #[serde(default)]
+    pub version: i64,
+    /// Glob patterns of more config files, relative to this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
     pub server: BaseConfig,
     pub web: Option<WebConfig>,
-    pub websocket_proxy: Option<ProxyConfig>,
-    pub tcp_proxy: Option<ProxyConfig>,
-    pub reverse_proxy: Option<ProxyConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub websocket_proxy: Vec<ProxyConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tcp_proxy: Vec<ProxyConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reverse_proxy: Vec<ProxyConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tcp_forward: Vec<ForwardConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub udp_forward: Vec<UdpForwardConfig>,
+}
+
+/// A file listed in `include`, it can add proxies, forwards and the web service but not change the server
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(dead_code)]
+pub struct Fragment {
+    #[serde(default)]
+    pub version: Option<i64>,
+    pub web: Option<WebConfig>,
+    #[serde(default)]
+    pub websocket_proxy: Vec<ProxyConfig>,
+    #[serde(default)]
+    pub tcp_proxy: Vec<ProxyConfig>,
+    #[serde(default)]
+    pub reverse_proxy: Vec<ProxyConfig>,
+    #[serde(default)]
+    pub tcp_forward: Vec<ForwardConfig>,
+    #[serde(default)]
+    pub udp_forward: Vec<UdpForwardConfig>,
+}
+
+/// Arrays of forwards, the instances of included files are appended like proxies
+pub const FORWARDS: [&str; 2] = ["tcp_forward", "udp_forward"];
+
+/// Connections accepted on `listen` and relayed as they are to `forward_to`
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct ForwardConfig {
+    pub name: String,
+    /// `host:port` to accept connections on
+    pub listen: String,
+    /// `host:port` of the backend, every connection gets its own connection to it
+    pub forward_to: String,
+    /// Milliseconds without data in either direction after which a connection is closed, 0 keeps it
+    #[serde(default, skip_serializing_if = "ProxyConfig::is_zero")]
+    pub idle_timeout: u64,
+    /// Connections forwarded at the same time, more are closed right away, 0 allows any number
+    #[serde(default, skip_serializing_if = "ProxyConfig::is_zero_count")]
+    pub max_connections: usize,
+}
+
+/// Datagrams received on `listen` and relayed to `forward_to`, each client address is a session
+/// with its own socket to the backend, so replies find their way back
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+pub struct UdpForwardConfig {
+    pub name: String,
+    /// `host:port` to receive datagrams on
+    pub listen: String,
+    /// `host:port` of the backend
+    pub forward_to: String,
+    /// Milliseconds without a datagram in either direction after which a session expires
+    #[serde(default = "UdpForwardConfig::default_idle_timeout", skip_serializing_if = "UdpForwardConfig::is_default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Sessions at the same time, datagrams of more clients are dropped, 0 allows any number
+    #[serde(default, skip_serializing_if = "ProxyConfig::is_zero_count")]
+    pub max_sessions: usize,
+    /// Bytes of the largest datagram relayed, larger ones are dropped
+    #[serde(default = "UdpForwardConfig::default_datagram_size", skip_serializing_if = "UdpForwardConfig::is_default_datagram_size")]
+    pub datagram_size: usize,
+    /// Bytes of the receive and send buffers of every socket, 0 keeps the system default
+    #[serde(default, skip_serializing_if = "ProxyConfig::is_zero_count")]
+    pub socket_buffer: usize,
+}
+
+impl UdpForwardConfig {
+    fn default_idle_timeout() -> u64 {
+        30000
+    }
+
+    fn is_default_idle_timeout(idle_timeout: &u64) -> bool {
+        *idle_timeout == Self::default_idle_timeout()
+    }
+
+    fn default_datagram_size() -> usize {
+        65535
+    }
+
+    fn is_default_datagram_size(datagram_size: &usize) -> bool {
+        *datagram_size == Self::default_datagram_size()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProxyKind {
+    WebSocket,
+    Tcp,
+    Reverse,
+}
+
+impl ProxyKind {
+    pub const ALL: [ProxyKind; 3] = [ProxyKind::WebSocket, ProxyKind::Tcp, ProxyKind::Reverse];
+
+    /// The key of this kind of proxy in the config file
+    pub fn key(&self) -> &'static str {
+        match self {
+            ProxyKind::WebSocket => "websocket_proxy",
+            ProxyKind::Tcp => "tcp_proxy",
+            ProxyKind::Reverse => "reverse_proxy",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.key() == key)
+    }
+}
+
+impl ServerConfig {
+    pub fn proxies(&self, kind: ProxyKind) -> &Vec<ProxyConfig> {
+        match kind {
+            ProxyKind::WebSocket => &self.websocket_proxy,
+            ProxyKind::Tcp => &self.tcp_proxy,
+            ProxyKind::Reverse => &self.reverse_proxy,
+        }
+    }
+
+    pub fn proxies_mut(&mut self, kind: ProxyKind) -> &mut Vec<ProxyConfig> {
+        match kind {
+            ProxyKind::WebSocket => &mut self.websocket_proxy,
+            ProxyKind::Tcp => &mut self.tcp_proxy,
+            ProxyKind::Reverse => &mut self.reverse_proxy,
+        }
+    }
+
+    pub fn proxy(&self, kind: ProxyKind, name: &str) -> Option<&ProxyConfig> {
+        self.proxies(kind).iter().find(|config| config.name == name)
+    }
+
+    pub fn proxy_mut(&mut self, kind: ProxyKind, name: &str) -> Option<&mut ProxyConfig> {
+        self.proxies_mut(kind).iter_mut().find(|config| config.name == name)
+    }
 }
@@ -0,0 +1,400 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use toml::{Table, Value};
+use super::schema::{self, Schema};
+use super::validate::Issue;
+
+pub const ENV_PREFIX: &str = "GATESERVER_";
+
+/// Where the effective value of a config key comes from, later ones take precedence
+#[derive(Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File(String),
+    Env(String),
+    Cli(&'static str),
+    Console,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(file) => write!(f, "file {file}"),
+            Source::Env(name) => write!(f, "env {name}"),
+            Source::Cli(flag) => write!(f, "cli {flag}"),
+            Source::Console => write!(f, "console"),
+        }
+    }
+}
+
+/// Sources of all leaf values by their dotted path, e.g. `tcp_proxy.default.timeout`
+pub type Sources = BTreeMap<String, Source>;
+
+/// Key of an array element in a path, the `name` of a proxy instance or its index
+pub fn element_key(index: usize, item: &Value) -> String {
+    item.get("name")
+        .and_then(Value::as_str)
+        .map_or_else(|| index.to_string(), str::to_string)
+}
+
+fn is_table_array(value: &Value) -> bool {
+    matches!(value, Value::Array(items) if !items.is_empty() && items.iter().all(Value::is_table))
+}
+
+pub fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{path}.{key}")
+    }
+}
+
+/// Visit all values that are neither tables nor arrays of tables
+pub fn for_each_leaf(path: &str, value: &Value, visit: &mut dyn FnMut(&str, &Value)) {
+    match value {
+        Value::Table(table) => {
+            for (key, child) in table {
+                for_each_leaf(&join(path, key), child, visit);
+            }
+        }
+        Value::Array(items) if is_table_array(value) => {
+            for (index, item) in items.iter().enumerate() {
+                for_each_leaf(&join(path, &element_key(index, item)), item, visit);
+            }
+        }
+        _ => visit(path, value),
+    }
+}
+
+fn child<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Table(table) => table.get(key),
+        Value::Array(items) => items.iter()
+            .enumerate()
+            .find(|(index, item)| element_key(*index, item) == key)
+            .map(|(_, item)| item),
+        _ => None,
+    }
+}
+
+fn child_mut<'a>(value: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    match value {
+        Value::Table(table) => table.get_mut(key),
+        Value::Array(items) => items.iter_mut()
+            .enumerate()
+            .find(|(index, item)| element_key(*index, item) == key)
+            .map(|(_, item)| item),
+        _ => None,
+    }
+}
+
+pub fn find<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, child)
+}
+
+pub fn find_mut<'a>(value: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    path.split('.').try_fold(value, child_mut)
+}
+
+/// Mark every leaf of `effective` as coming from the first of `files` that sets it
+pub fn track(effective: &Value, files: &[(&str, &Value)]) -> Sources {
+    let mut sources = Sources::new();
+    for_each_leaf("", effective, &mut |path, _| {
+        let source = files.iter()
+            .find(|(_, file)| find(file, path).is_some())
+            .map_or(Source::Default, |(name, _)| Source::File(name.to_string()));
+        sources.insert(path.to_string(), source);
+    });
+    sources
+}
+
+/// Convert `raw` to a value of the TOML type `kind`
+pub fn coerce(raw: &str, kind: &str) -> Result<Value, String> {
+    match kind {
+        "string" => Ok(Value::String(raw.to_string())),
+        "integer" => raw.trim().parse().map(Value::Integer)
+            .map_err(|_| format!("`{raw}` is not an integer")),
+        "float" => raw.trim().parse().map(Value::Float)
+            .map_err(|_| format!("`{raw}` is not a number")),
+        "boolean" => match raw.trim().to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => Ok(Value::Boolean(true)),
+            "false" | "0" | "no" | "off" => Ok(Value::Boolean(false)),
+            _ => Err(format!("`{raw}` is not a boolean")),
+        },
+        _ => {
+            let value = toml::from_str::<Table>(&format!("value = {raw}"))
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .ok_or_else(|| format!("`{raw}` is not a valid {kind}"))?;
+            if kind != "any" && value.type_str() != kind {
+                return Err(format!("expected {kind}, found {}", value.type_str()));
+            }
+            Ok(value)
+        }
+    }
+}
+
+fn env_segment(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+/// How the segments of a path are compared to the keys of the config
+struct Matching {
+    matches: fn(&str, &str) -> bool,
+    /// The key of an instance or map entry that does not exist yet
+    new_key: fn(&str) -> String,
+}
+
+const EXACT: Matching = Matching {
+    matches: |key, segment| key == segment,
+    new_key: str::to_string,
+};
+
+/// Env var names are upper case, new instances and map entries get the lower case name
+const ENV: Matching = Matching {
+    matches: |key, segment| env_segment(key) == segment,
+    new_key: str::to_ascii_lowercase,
+};
+
+/// Resolve segments to the path of a leaf of `schema` and return its TOML type. `value` is what
+/// the config has at that point, keys it does not have are found in the schema.
+/// The instance name may be left out for proxy types with a single instance
+fn resolve(schema: &Schema, value: Option<&Value>, segments: &[String], path: &mut Vec<String>, matching: &Matching) -> Option<&'static str> {
+    let Some((first, rest)) = segments.split_first() else {
+        return match schema {
+            Schema::Value(kind) => Some(kind),
+            _ => None,
+        };
+    };
+    match schema {
+        Schema::Value(_) => None,
+        Schema::Table(fields) => {
+            let (key, child) = fields.iter().find(|(key, _)| (matching.matches)(key, first))?;
+            path.push(key.to_string());
+            resolve(child, value.and_then(|value| value.get(key)), rest, path, matching)
+        }
+        Schema::Map(entry) => {
+            let key = value.and_then(Value::as_table)
+                .and_then(|table| table.keys().find(|key| (matching.matches)(key, first)).cloned())
+                .unwrap_or_else(|| (matching.new_key)(first));
+            let child = value.and_then(|value| value.get(&key));
+            path.push(key);
+            resolve(entry, child, rest, path, matching)
+        }
+        Schema::Array(element) => {
+            let items = value.and_then(Value::as_array).map_or(&[][..], Vec::as_slice);
+            let keys = items.iter()
+                .enumerate()
+                .map(|(index, item)| element_key(index, item))
+                .collect::<Vec<_>>();
+            if let Some(index) = keys.iter().position(|key| (matching.matches)(key, first)) {
+                path.push(keys[index].clone());
+                return resolve(element, Some(&items[index]), rest, path, matching);
+            }
+            let mark = path.len();
+            if items.len() == 1 {
+                path.push(keys[0].clone());
+                if let Some(kind) = resolve(element, Some(&items[0]), segments, path, matching) {
+                    return Some(kind);
+                }
+                path.truncate(mark);
+            }
+            // a new element, named like an instance or appended by its index
+            let key = if element.is_named() {
+                (matching.new_key)(first)
+            } else if *first == items.len().to_string() {
+                first.clone()
+            } else {
+                return None;
+            };
+            path.push(key);
+            resolve(element, None, rest, path, matching)
+        }
+    }
+}
+
+/// The full path of the leaf at `path` and its TOML type, e.g. `reverse_proxy.default.timeout`
+/// for `reverse_proxy.timeout`. The leaf does not have to be set
+pub fn resolve_path(tree: &Value, path: &str) -> Option<(String, &'static str)> {
+    let segments = path.split('.').map(str::to_string).collect::<Vec<_>>();
+    let mut resolved = Vec::new();
+    let kind = resolve(schema::config(), Some(tree), &segments, &mut resolved, &EXACT)?;
+    Some((resolved.join("."), kind))
+}
+
+/// Set the leaf at the resolved `path`, creating the tables and instances it is in
+pub fn set(tree: &mut Value, path: &str, leaf: Value) {
+    let segments = path.split('.').collect::<Vec<_>>();
+    place(schema::config(), tree, &segments, leaf);
+}
+
+fn place(schema: &Schema, value: &mut Value, segments: &[&str], leaf: Value) {
+    let Some((first, rest)) = segments.split_first() else {
+        *value = leaf;
+        return;
+    };
+    match schema {
+        Schema::Table(_) | Schema::Map(_) => {
+            let Some(table) = value.as_table_mut() else {
+                return;
+            };
+            let child_schema = match schema {
+                Schema::Map(entry) => entry,
+                _ => schema.field(first).unwrap(),
+            };
+            let child = table.entry(first.to_string()).or_insert_with(|| empty(child_schema));
+            place(child_schema, child, rest, leaf);
+        }
+        Schema::Array(element) => {
+            let Some(items) = value.as_array_mut() else {
+                return;
+            };
+            let index = match items.iter().enumerate().position(|(index, item)| element_key(index, item) == *first) {
+                Some(index) => index,
+                None => {
+                    let mut item = Table::new();
+                    if element.is_named() {
+                        item.insert(String::from("name"), Value::String(first.to_string()));
+                    }
+                    items.push(Value::Table(item));
+                    items.len() - 1
+                }
+            };
+            place(element, &mut items[index], rest, leaf);
+        }
+        Schema::Value(_) => {}
+    }
+}
+
+fn empty(schema: &Schema) -> Value {
+    match schema {
+        Schema::Array(_) => Value::Array(Vec::new()),
+        _ => Value::Table(Table::new()),
+    }
+}
+
+/// Apply all `GATESERVER_` env vars, e.g. `GATESERVER_SERVER__PORT` or `GATESERVER_TCP_PROXY__DEVICES__FORWARD_TO`
+pub fn apply_env(tree: &mut Value, sources: &mut Sources, issues: &mut Vec<Issue>) {
+    let mut vars = std::env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect::<Vec<_>>();
+    vars.sort();
+    for (name, raw) in vars {
+        let segments = name[ENV_PREFIX.len()..]
+            .split("__")
+            .map(str::to_ascii_uppercase)
+            .collect::<Vec<_>>();
+        let mut path = Vec::new();
+        let Some(kind) = resolve(schema::config(), Some(tree), &segments, &mut path, &ENV) else {
+            issues.push(Issue::warning(&Source::Env(name).to_string(), "does not match any config key, ignored"));
+            continue;
+        };
+        let path = path.join(".");
+        match coerce(&raw, kind) {
+            Ok(value) => {
+                set(tree, &path, value);
+                sources.insert(path, Source::Env(name));
+            }
+            Err(message) => issues.push(Issue {
+                key: path,
+                ..Issue::error(&Source::Env(name).to_string(), message)
+            }),
+        }
+    }
+}
+
+/// Render the config as TOML with the source of every value as a comment
+pub fn annotate(config: &Value, sources: &Sources) -> String {
+    let mut out = String::new();
+    if let Some(table) = config.as_table() {
+        render_table(&mut out, "", "", table, sources);
+    }
+    out.trim_start().to_string()
+}
+
+fn render_table(out: &mut String, header: &str, path: &str, table: &Table, sources: &Sources) {
+    for (key, value) in table {
+        if value.is_table() || is_table_array(value) {
+            continue;
+        }
+        let line = format!("{key} = {value}");
+        match sources.get(&join(path, key)) {
+            Some(source) => writeln!(out, "{line:<40} # {source}").unwrap(),
+            None => writeln!(out, "{line}").unwrap(),
+        }
+    }
+    for (key, value) in table {
+        match value {
+            Value::Table(child) => {
+                write!(out, "\n[{}]\n", join(header, key)).unwrap();
+                render_table(out, &join(header, key), &join(path, key), child, sources);
+            }
+            Value::Array(items) if is_table_array(value) => {
+                for (index, item) in items.iter().enumerate() {
+                    write!(out, "\n[[{}]]\n", join(header, key)).unwrap();
+                    let item_path = join(&join(path, key), &element_key(index, item));
+                    render_table(out, &join(header, key), &item_path, item.as_table().unwrap(), sources);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(source: &str) -> Value {
+        toml::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn resolves_paths_against_the_schema() {
+        let config = tree("[[tcp_proxy]]\nname = \"devices\"\n");
+        for (path, resolved, kind) in [
+            ("server.port", "server.port", "integer"),
+            ("tcp_proxy.devices.timeout", "tcp_proxy.devices.timeout", "integer"),
+            // a single instance can be left out
+            ("tcp_proxy.timeout", "tcp_proxy.devices.timeout", "integer"),
+            ("tcp_proxy.sensors.path", "tcp_proxy.sensors.path", "string"),
+            ("tcp_proxy.devices.handshake.headers.origin", "tcp_proxy.devices.handshake.headers.origin", "string"),
+            ("server.listeners.0.plain", "server.listeners.0.plain", "boolean"),
+        ] {
+            assert_eq!(resolve_path(&config, path), Some((resolved.to_string(), kind)), "{path}");
+        }
+        assert_eq!(resolve_path(&config, "server.missing"), None);
+        assert_eq!(resolve_path(&config, "server"), None);
+        // unnamed elements are appended by their index only
+        assert_eq!(resolve_path(&config, "server.listeners.1.plain"), None);
+    }
+
+    #[test]
+    fn set_creates_tables_and_instances() {
+        let mut config = tree("[server]\nport = 80\n");
+        set(&mut config, "tcp_proxy.devices.framing.codec", Value::String(String::from("lines")));
+        set(&mut config, "server.listeners.0.address", Value::String(String::from("unix:/run/gs.sock")));
+        assert_eq!(config, tree(r#"
+            [server]
+            port = 80
+            [[server.listeners]]
+            address = "unix:/run/gs.sock"
+            [[tcp_proxy]]
+            name = "devices"
+            framing = { codec = "lines" }
+        "#));
+    }
+
+    #[test]
+    fn coerces_raw_values() {
+        assert_eq!(coerce("8080", "integer"), Ok(Value::Integer(8080)));
+        assert_eq!(coerce("on", "boolean"), Ok(Value::Boolean(true)));
+        assert_eq!(coerce("8080", "string"), Ok(Value::String(String::from("8080"))));
+        assert_eq!(coerce(r#"["web", "api"]"#, "array"), Ok(tree(r#"v = ["web", "api"]"#)["v"].clone()));
+        assert!(coerce("eighty", "integer").is_err());
+        assert!(coerce("\"web\"", "array").is_err());
+    }
+}
@@ -0,0 +1,573 @@
+use std::fmt;
+use std::path::Path;
+use axum::http::{HeaderName, HeaderValue, Uri, header};
+use toml_edit::ImDocument;
+use tracing_subscriber::EnvFilter;
+use super::{ServerConfig, ProxyKind, FrameType, FramingCodec, ResponseEnd};
+use crate::services::framing::MAX_FRAME;
+use super::server_config::SERVICES;
+use super::migrate::CONFIG_VERSION;
+use super::sources::{Source, Sources};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a config file, located by its key and line when possible
+pub struct Issue {
+    pub severity: Severity,
+    pub file: String,
+    pub line: Option<usize>,
+    pub key: String,
+    pub message: String,
+}
+
+impl Issue {
+    pub fn error(file: &str, message: impl Into<String>) -> Self {
+        Self { severity: Severity::Error, file: file.to_string(), line: None, key: String::new(), message: message.into() }
+    }
+
+    pub fn warning(file: &str, message: impl Into<String>) -> Self {
+        Self { severity: Severity::Warning, ..Self::error(file, message) }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file)?;
+        if let Some(line) = self.line {
+            write!(f, ":{line}")?;
+        }
+        match self.severity {
+            Severity::Error => write!(f, ": error: ")?,
+            Severity::Warning => write!(f, ": warning: ")?,
+        }
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// All problems of a config that could not be loaded
+pub struct ConfigError {
+    pub issues: Vec<Issue>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for issue in &self.issues {
+            write!(f, "\n  {issue}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<Issue> for ConfigError {
+    fn from(issue: Issue) -> Self {
+        Self { issues: vec![issue] }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Segment<'a> {
+    Key(&'static str),
+    /// An element of an array of tables, by its index and its `name`
+    Instance(usize, &'a str),
+}
+
+struct LocatedFile {
+    name: String,
+    source: String,
+    document: Option<ImDocument<String>>,
+}
+
+/// Maps keys of the parsed config back to lines of the file that sets them, or to
+/// the env var or command line option that overrides them
+pub struct Locator<'a> {
+    /// the config file first, then the files it includes
+    files: Vec<LocatedFile>,
+    sources: Option<&'a Sources>,
+}
+
+impl<'a> Locator<'a> {
+    pub fn new(file: &str, source: &str) -> Self {
+        let file = LocatedFile {
+            name: file.to_string(),
+            source: source.to_string(),
+            document: ImDocument::parse(source.to_string()).ok(),
+        };
+        Self { files: vec![file], sources: None }
+    }
+
+    pub fn with_sources(self, sources: &'a Sources) -> Self {
+        Self { sources: Some(sources), ..self }
+    }
+
+    /// Also locate keys in the files of `other`
+    pub fn include(&mut self, other: Locator) {
+        self.files.extend(other.files);
+    }
+
+    pub fn parse_error(&self, err: &toml::de::Error) -> Issue {
+        let file = &self.files[0];
+        Issue {
+            line: err.span().map(|span| line_at(&file.source, span.start)),
+            ..Issue::error(&file.name, err.message().trim())
+        }
+    }
+
+    pub fn issue(&self, severity: Severity, path: &[Segment], message: String) -> Issue {
+        let source = self.sources.and_then(|sources| sources.get(&source_path(path)));
+        if let Some(source @ (Source::Env(_) | Source::Cli(_))) = source {
+            return Issue { severity, key: key_of(path), message, ..Issue::error(&source.to_string(), "") };
+        }
+        let file = match source {
+            Some(Source::File(name)) => self.files.iter().find(|file| file.name == *name),
+            _ => None,
+        }.unwrap_or(&self.files[0]);
+        Issue {
+            severity,
+            file: file.name.clone(),
+            line: line(file, path),
+            key: key_of(path),
+            message,
+        }
+    }
+}
+
+fn line(file: &LocatedFile, path: &[Segment]) -> Option<usize> {
+    let mut item = file.document.as_ref()?.as_item();
+    // point at the closest key that exists in the file
+    let mut span = None;
+    for segment in path {
+        let next = match segment {
+            Segment::Key(key) => item.get(*key),
+            // instances are found by name, the index differs when the file is not the only one defining them
+            Segment::Instance(index, name) => {
+                let position = item.as_array_of_tables()
+                    .and_then(|instances| instances.iter()
+                        .position(|table| table.get("name").and_then(|name| name.as_str()) == Some(*name)));
+                item.get(position.unwrap_or(*index))
+            }
+        };
+        match next {
+            Some(next) => item = next,
+            None => break,
+        }
+        span = item.span().or(span);
+    }
+    span.map(|span| line_at(&file.source, span.start))
+}
+
+fn line_at(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+fn key_of(path: &[Segment]) -> String {
+    let mut key = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(name) if key.is_empty() => key.push_str(name),
+            Segment::Key(name) => key.push_str(&format!(".{name}")),
+            Segment::Instance(index, _) => key.push_str(&format!("[{index}]")),
+        }
+    }
+    key
+}
+
+/// The dotted path used for sources, instances are addressed by name
+fn source_path(path: &[Segment]) -> String {
+    path.iter()
+        .map(|segment| match segment {
+            Segment::Key(key) => *key,
+            Segment::Instance(_, name) => name,
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Check everything that deserializing alone does not catch
+pub fn validate(config: &ServerConfig, locator: &Locator) -> Vec<Issue> {
+    use Segment::{Key, Instance};
+
+    let mut issues = Vec::new();
+    let mut error = |path: &[Segment], message: String| issues.push(locator.issue(Severity::Error, path, message));
+
+    if config.version != CONFIG_VERSION {
+        error(&[Key("version")], format!("version must be {CONFIG_VERSION}"));
+    }
+    if !(1..=65535).contains(&config.server.port) {
+        error(&[Key("server"), Key("port")], format!("port {} is out of range 1-65535", config.server.port));
+    }
+    if let Err(message) = check_log_level(&config.server.log_level) {
+        error(&[Key("server"), Key("log_level")], message);
+    }
+    if config.server.listeners.is_empty() && config.server.host.is_empty() {
+        error(&[Key("server"), Key("host")], String::from("host must not be empty"));
+    }
+    let indices = (0..config.server.listeners.len()).map(|index| index.to_string()).collect::<Vec<_>>();
+    // reported after the errors, `error` borrows `issues` until then
+    let mut warnings: Vec<(Vec<Segment>, String)> = Vec::new();
+    for (index, listener) in config.server.listeners.iter().enumerate() {
+        let at = |key| [Key("server"), Key("listeners"), Instance(index, &indices[index]), Key(key)];
+        if let Err(message) = check_listen_address(&listener.address) {
+            error(&at("address"), message);
+        } else if config.server.listeners[..index].iter().any(|other| other.address == listener.address) {
+            error(&at("address"), format!("address `{}` is used by more than one listener", listener.address));
+        }
+        for service in listener.services.iter().flatten() {
+            match service.split_once('.') {
+                None if SERVICES.contains(&service.as_str()) => {}
+                Some((kind, name)) => match ProxyKind::from_key(kind) {
+                    Some(kind) if config.proxy(kind, name).is_some() => {}
+                    Some(kind) => warnings.push((at("services").to_vec(), format!("there is no {} named `{name}`", kind.key()))),
+                    None => error(&at("services"), format!("unknown service `{service}`, expected one of {}", SERVICES.join(", "))),
+                },
+                None => error(&at("services"), format!("unknown service `{service}`, expected one of {}", SERVICES.join(", "))),
+            }
+        }
+    }
+
+    // every mounted path with the key that mounts it, `/api` is always served
+    let mut mounts = vec![(String::from("/api"), String::from("the API service"))];
+    if let Some(web) = &config.web {
+        let path = [Key("web"), Key("path")];
+        if let Err(message) = check_mount(&mut mounts, &web.path, key_of(&path)) {
+            error(&path, message);
+        }
+    }
+    for kind in ProxyKind::ALL {
+        let proxies = config.proxies(kind);
+        for (index, proxy) in proxies.iter().enumerate() {
+            let at = |key| [Key(kind.key()), Instance(index, &proxy.name), Key(key)];
+            if proxy.name.is_empty() {
+                error(&at("name"), String::from("name must not be empty"));
+            } else if proxy.name.contains('.') {
+                error(&at("name"), format!("name `{}` must not contain `.`", proxy.name));
+            } else if proxies[..index].iter().any(|other| other.name == proxy.name) {
+                error(&at("name"), format!("name `{}` is used by more than one {}", proxy.name, kind.key()));
+            }
+            if let Err(message) = check_mount(&mut mounts, &proxy.path, key_of(&at("path"))) {
+                error(&at("path"), message);
+            }
+            let target = match kind {
+                ProxyKind::WebSocket => check_url(&proxy.forward_to, &["ws"]),
+                ProxyKind::Tcp => check_socket_address(&proxy.forward_to),
+                ProxyKind::Reverse => check_url(&proxy.forward_to, &["http"]),
+            };
+            if let Err(message) = target {
+                error(&at("forward_to"), message);
+            }
+            if let Some(path) = proxy.events_path() {
+                let key = [Key(kind.key()), Instance(index, &proxy.name), Key("events"), Key("path")];
+                if kind == ProxyKind::Reverse {
+                    error(&at("events"), String::from("events are only supported by websocket_proxy and tcp_proxy"));
+                } else if let Err(message) = check_mount(&mut mounts, &path, key_of(&key)) {
+                    error(&key, message);
+                }
+            }
+            if let Some(envelope) = &proxy.envelope {
+                if kind != ProxyKind::WebSocket {
+                    error(&at("envelope"), format!("envelope is only supported by websocket_proxy, not by {}", kind.key()));
+                } else if envelope.field.is_empty() {
+                    error(&[Key(kind.key()), Instance(index, &proxy.name), Key("envelope"), Key("field")], String::from("field must not be empty"));
+                }
+            }
+            if proxy.is_pooled() && kind == ProxyKind::Reverse {
+                error(&at("max_connections"), String::from("connection pools are only supported by websocket_proxy and tcp_proxy"));
+            } else if proxy.max_connections == 0 {
+                error(&at("max_connections"), String::from("max_connections must be at least 1"));
+            } else if proxy.min_connections > proxy.max_connections {
+                error(&at("min_connections"), format!("min_connections must not be more than max_connections ({})", proxy.max_connections));
+            } else if proxy.envelope.is_some() && proxy.is_pooled() {
+                warnings.push((at("max_connections").to_vec(), String::from("requests with an envelope share one connection, the pool settings are ignored")));
+            }
+            if proxy.max_backoff == 0 {
+                error(&at("max_backoff"), String::from("max_backoff must be at least 1"));
+            }
+            if proxy.frame != FrameType::Binary {
+                if kind != ProxyKind::WebSocket {
+                    error(&at("frame"), format!("frame is only supported by websocket_proxy, not by {}", kind.key()));
+                } else if proxy.envelope.is_some() {
+                    error(&at("frame"), String::from("the message type of requests with an envelope is decided by its format"));
+                }
+            }
+            if let Err(message) = check_content_type(&proxy.content_type, kind) {
+                error(&at("content_type"), message);
+            }
+            if proxy.passthrough && kind != ProxyKind::WebSocket {
+                error(&at("passthrough"), format!("passthrough is only supported by websocket_proxy, not by {}", kind.key()));
+            }
+            if let Some(response) = &proxy.response {
+                let at = |key| [Key(kind.key()), Instance(index, &proxy.name), Key("response"), Key(key)];
+                if kind == ProxyKind::Reverse {
+                    error(&at("until"), String::from("response is only supported by websocket_proxy and tcp_proxy"));
+                } else if proxy.envelope.is_some() {
+                    error(&at("until"), String::from("responses with an envelope are single messages carrying the ID"));
+                }
+                match response.until {
+                    ResponseEnd::Count if kind == ProxyKind::Tcp && proxy.framing.is_none() => {
+                        error(&at("until"), String::from("`count` needs framing on tcp_proxy, TCP has no message boundaries otherwise"));
+                    }
+                    ResponseEnd::Count if response.count == 0 => error(&at("count"), String::from("count must be at least 1")),
+                    ResponseEnd::Terminator if response.terminator.is_empty() => error(&at("terminator"), String::from("terminator must not be empty")),
+                    ResponseEnd::Idle if response.idle == 0 => error(&at("idle"), String::from("idle must be at least 1")),
+                    _ => {}
+                }
+            }
+            if let Some(framing) = &proxy.framing {
+                let at = |key| [Key(kind.key()), Instance(index, &proxy.name), Key("framing"), Key(key)];
+                if kind != ProxyKind::Tcp {
+                    error(&at("codec"), format!("framing is only supported by tcp_proxy, not by {}", kind.key()));
+                }
+                match framing.codec {
+                    FramingCodec::Fixed if framing.size == 0 || framing.size > MAX_FRAME => {
+                        error(&at("size"), format!("size must be between 1 and {MAX_FRAME}"));
+                    }
+                    FramingCodec::Delimiter if framing.delimiter.is_empty() => error(&at("delimiter"), String::from("delimiter must not be empty")),
+                    _ => {}
+                }
+            }
+            if let Some(handshake) = &proxy.handshake {
+                let at = |key| [Key(kind.key()), Instance(index, &proxy.name), Key("handshake"), Key(key)];
+                if kind != ProxyKind::WebSocket {
+                    error(&[Key(kind.key()), Instance(index, &proxy.name), Key("handshake")],
+                        format!("handshake is only supported by websocket_proxy, not by {}", kind.key()));
+                }
+                for (name, value) in &handshake.headers {
+                    let message = match check_handshake_header(name) {
+                        Err(message) => message,
+                        Ok(()) if HeaderValue::from_str(value).is_err() => format!("`{value}` is not a valid value for `{name}`"),
+                        Ok(()) => continue,
+                    };
+                    error(&at("headers"), message);
+                }
+                if let Some(message) = handshake.forward_headers.iter().find_map(|name| check_handshake_header(name).err()) {
+                    error(&at("forward_headers"), message);
+                } else if !handshake.forward_headers.is_empty() && !proxy.passthrough {
+                    warnings.push((at("forward_headers").to_vec(), String::from("headers are only forwarded for passthrough connections, enable passthrough to use them")));
+                }
+                if let Some(protocol) = handshake.protocols.iter().find(|protocol| !is_token(protocol)) {
+                    error(&at("protocols"), format!("`{protocol}` is not a valid subprotocol name"));
+                }
+            }
+        }
+    }
+
+    if let Some(tls) = &config.server.tls {
+        let at = |key| [Key("server"), Key("tls"), Key(key)];
+        match (&tls.cert, &tls.key) {
+            (Some(cert), Some(key)) => if let Err(message) = check_certificate(cert, key) {
+                error(&at("cert"), message);
+            },
+            (Some(_), None) => error(&at("key"), String::from("key must be set together with cert")),
+            (None, Some(_)) => error(&at("cert"), String::from("cert must be set together with key")),
+            (None, None) if tls.certs.is_empty() => error(&at("cert"), String::from("set cert and key, or add [[server.tls.certs]]")),
+            (None, None) => {}
+        }
+        let indices = (0..tls.certs.len()).map(|index| index.to_string()).collect::<Vec<_>>();
+        for (index, entry) in tls.certs.iter().enumerate() {
+            let at = |key| [Key("server"), Key("tls"), Key("certs"), Instance(index, &indices[index]), Key(key)];
+            if entry.names.is_empty() {
+                error(&at("names"), String::from("names must not be empty"));
+            }
+            for name in &entry.names {
+                let duplicate = tls.certs[..index].iter().any(|other| other.names.iter().any(|other| other.eq_ignore_ascii_case(name)));
+                if duplicate {
+                    error(&at("names"), format!("`{name}` is served by more than one certificate"));
+                }
+            }
+            if let Err(message) = check_certificate(&entry.cert, &entry.key) {
+                error(&at("cert"), message);
+            }
+        }
+        if let Some(address) = &tls.redirect_http {
+            if let Err(message) = check_socket_address(address) {
+                error(&at("redirect_http"), message);
+            }
+        }
+        if let Some(client_auth) = &tls.client_auth {
+            let at = |key| [Key("server"), Key("tls"), Key("client_auth"), Key(key)];
+            if let Err(message) = crate::tls::client_verifier(client_auth) {
+                error(&at("ca"), message);
+            }
+            for path in client_auth.paths.iter().flatten() {
+                if !path.starts_with('/') {
+                    error(&at("paths"), format!("path `{path}` must start with `/`"));
+                }
+            }
+            if HeaderName::try_from(client_auth.header.as_str()).is_err() {
+                error(&at("header"), format!("`{}` is not a valid header name", client_auth.header));
+            }
+            if client_auth.field.as_ref().is_some_and(String::is_empty) {
+                error(&at("field"), String::from("field must not be empty"));
+            }
+        }
+        if config.server.https_port().is_none() {
+            warnings.push((at("cert").to_vec(), String::from("no listener serves HTTPS, all of them are plain or Unix sockets")));
+        }
+    }
+    // addresses bound by the HTTP server, a forward can not listen on them as well
+    let mut bound = config.server.listeners().into_iter().map(|listener| listener.address).collect::<Vec<_>>();
+    bound.extend(config.server.tls.as_ref().and_then(|tls| tls.redirect_http.clone()));
+    for (index, forward) in config.tcp_forward.iter().enumerate() {
+        let at = |key| [Key("tcp_forward"), Instance(index, &forward.name), Key(key)];
+        if forward.name.is_empty() {
+            error(&at("name"), String::from("name must not be empty"));
+        } else if config.tcp_forward[..index].iter().any(|other| other.name == forward.name) {
+            error(&at("name"), format!("name `{}` is used by more than one tcp_forward", forward.name));
+        }
+        if let Err(message) = check_socket_address(&forward.listen) {
+            error(&at("listen"), message);
+        } else if bound.contains(&forward.listen) {
+            error(&at("listen"), format!("address `{}` is already used by the server", forward.listen));
+        } else if config.tcp_forward[..index].iter().any(|other| other.listen == forward.listen) {
+            error(&at("listen"), format!("address `{}` is used by more than one tcp_forward", forward.listen));
+        }
+        if let Err(message) = check_socket_address(&forward.forward_to) {
+            error(&at("forward_to"), message);
+        }
+    }
+    for (index, forward) in config.udp_forward.iter().enumerate() {
+        let at = |key| [Key("udp_forward"), Instance(index, &forward.name), Key(key)];
+        if forward.name.is_empty() {
+            error(&at("name"), String::from("name must not be empty"));
+        } else if config.udp_forward[..index].iter().any(|other| other.name == forward.name) {
+            error(&at("name"), format!("name `{}` is used by more than one udp_forward", forward.name));
+        }
+        if let Err(message) = check_socket_address(&forward.listen) {
+            error(&at("listen"), message);
+        } else if config.udp_forward[..index].iter().any(|other| other.listen == forward.listen) {
+            error(&at("listen"), format!("address `{}` is used by more than one udp_forward", forward.listen));
+        }
+        if let Err(message) = check_socket_address(&forward.forward_to) {
+            error(&at("forward_to"), message);
+        }
+        if forward.idle_timeout == 0 {
+            error(&at("idle_timeout"), String::from("idle_timeout must be at least 1, sessions would never expire"));
+        }
+        if !(1..=65535).contains(&forward.datagram_size) {
+            error(&at("datagram_size"), format!("datagram_size {} is out of range 1-65535", forward.datagram_size));
+        }
+    }
+    for (path, message) in warnings {
+        issues.push(locator.issue(Severity::Warning, &path, message));
+    }
+    if let Some(web) = &config.web {
+        if !Path::new(&web.dist_path).is_dir() {
+            issues.push(locator.issue(Severity::Warning, &[Key("web"), Key("dist_path")],
+                format!("directory `{}` does not exist, the web service will not find any file", web.dist_path)));
+        }
+    }
+    issues
+}
+
+fn check_mount(mounts: &mut Vec<(String, String)>, path: &str, key: String) -> Result<(), String> {
+    if !path.starts_with('/') {
+        return Err(format!("path `{path}` must start with `/`"));
+    }
+    let normalized = if path == "/" { path } else { path.trim_end_matches('/') };
+    if let Some((_, owner)) = mounts.iter().find(|(mounted, _)| mounted == normalized) {
+        return Err(format!("path `{path}` is already used by {owner}"));
+    }
+    mounts.push((normalized.to_string(), key));
+    Ok(())
+}
+
+fn check_log_level(filter: &str) -> Result<(), String> {
+    EnvFilter::try_new(filter)
+        .map(|_| ())
+        .map_err(|err| format!("invalid log filter `{filter}`: {err}"))
+}
+
+fn check_content_type(content_type: &str, kind: ProxyKind) -> Result<(), String> {
+    use crate::services::content_type::{MESSAGE, SNIFF};
+    match (content_type, kind) {
+        // the reverse proxy passes on the Content-Type of the backend
+        ("application/json", ProxyKind::Reverse) => Ok(()),
+        (_, ProxyKind::Reverse) => Err(String::from("content_type is only supported by websocket_proxy and tcp_proxy")),
+        (MESSAGE, ProxyKind::Tcp) => Err(format!("`{MESSAGE}` is only supported by websocket_proxy, TCP has no message types")),
+        (SNIFF | MESSAGE, _) => Ok(()),
+        (fixed, _) => {
+            let essence = fixed.split(';').next().unwrap_or_default().trim();
+            match essence.split_once('/') {
+                Some((main, sub)) if !main.is_empty() && !sub.is_empty() && HeaderValue::from_str(fixed).is_ok() => Ok(()),
+                _ => Err(format!("`{fixed}` is not a media type like `application/json`, `{SNIFF}` or `{MESSAGE}`")),
+            }
+        }
+    }
+}
+
+/// Headers the WebSocket handshake sets itself
+const HANDSHAKE_HEADERS: [&str; 7] = [
+    "host", "connection", "upgrade", "sec-websocket-key", "sec-websocket-version", "sec-websocket-protocol", "sec-websocket-extensions",
+];
+
+fn check_handshake_header(name: &str) -> Result<(), String> {
+    let Ok(name) = HeaderName::try_from(name) else {
+        return Err(format!("`{name}` is not a valid header name"));
+    };
+    if name == header::SEC_WEBSOCKET_PROTOCOL {
+        return Err(format!("`{name}` is set by the WebSocket handshake, subprotocols are configured with `protocols`"));
+    }
+    if HANDSHAKE_HEADERS.contains(&name.as_str()) {
+        return Err(format!("`{name}` is set by the WebSocket handshake"));
+    }
+    Ok(())
+}
+
+/// Whether `value` is a token of HTTP, like the names of subprotocols
+fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+fn check_url(url: &str, schemes: &[&str]) -> Result<(), String> {
+    let uri = url.parse::<Uri>()
+        .map_err(|err| format!("`{url}` is not a valid URL: {err}"))?;
+    match uri.scheme_str() {
+        Some(scheme) if schemes.contains(&scheme) => {}
+        _ => return Err(format!("`{url}` must start with {}", schemes.iter()
+            .map(|scheme| format!("{scheme}://"))
+            .collect::<Vec<_>>()
+            .join(" or "))),
+    }
+    if uri.host().is_none_or(str::is_empty) {
+        return Err(format!("`{url}` has no host"));
+    }
+    Ok(())
+}
+
+fn check_certificate(cert: &str, key: &str) -> Result<(), String> {
+    crate::tls::certified_key(cert, key).map(|_| ())
+}
+
+fn check_listen_address(address: &str) -> Result<(), String> {
+    match address.strip_prefix("unix:") {
+        Some("") => Err(format!("`{address}` has no socket path")),
+        Some(_) if cfg!(unix) => Ok(()),
+        Some(_) => Err(format!("`{address}` is a Unix socket, which this platform does not support")),
+        None => check_socket_address(address),
+    }
+}
+
+fn check_socket_address(address: &str) -> Result<(), String> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => match port.parse::<u16>() {
+            Ok(port) if port != 0 => Ok(()),
+            _ => Err(format!("`{port}` in `{address}` is not a valid port")),
+        },
+        _ => Err(format!("`{address}` is not a valid address, expected `host:port`")),
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex, OnceLock, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use socket2::SockRef;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    select,
+    task::AbortHandle,
+    time::{Duration, Instant}
+};
+use crate::config::{ForwardConfig, UdpForwardConfig};
+use crate::supervisor::CONNECT_TIMEOUT;
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Connections and traffic of a TCP forward, kept when a reload changes its settings
+#[derive(Default)]
+pub struct Stats {
+    active: AtomicUsize,
+    accepted: AtomicU64,
+    /// Closed right away, `max_connections` were forwarded already
+    refused: AtomicU64,
+    /// The backend could not be reached
+    failed: AtomicU64,
+    /// Bytes from clients to the backend
+    sent: AtomicU64,
+    /// Bytes from the backend to clients
+    received: AtomicU64,
+}
+
+impl Stats {
+    pub fn describe(&self) -> String {
+        format!("{} active, {} accepted, {} refused, {} failed, {} sent, {} received",
+            self.active.load(Ordering::Relaxed),
+            self.accepted.load(Ordering::Relaxed),
+            self.refused.load(Ordering::Relaxed),
+            self.failed.load(Ordering::Relaxed),
+            format_bytes(self.sent.load(Ordering::Relaxed)),
+            format_bytes(self.received.load(Ordering::Relaxed)))
+    }
+}
+
+/// Accepts connections on the `listen` address of a `[[tcp_forward]]` and relays each one to
+/// its own connection to the backend
+pub struct TcpForward {
+    config: ForwardConfig,
+    /// Taken when the forward stops, a context that outlives a reload must not keep the address bound
+    listener: StdMutex<Option<Arc<TcpListener>>>,
+    stats: Arc<Stats>,
+    accept: AbortHandle,
+}
+
+impl TcpForward {
+    /// Start accepting connections for `config`. The listener and counters of `previous` are
+    /// taken over when it listens on the same address, it stops accepting then
+    pub fn start(config: &ForwardConfig, previous: Option<&TcpForward>) -> std::io::Result<Arc<Self>> {
+        let taken = previous.filter(|previous| previous.listens_on(&config.listen))
+            .and_then(|previous| previous.stop().map(|listener| (listener, previous.stats.clone())));
+        let (listener, stats) = match taken {
+            Some(taken) => taken,
+            None => (Arc::new(bind(&config.listen)?), Arc::new(Stats::default())),
+        };
+        let accept = tokio::spawn(accept(listener.clone(), config.clone(), stats.clone())).abort_handle();
+        tracing::info!("TCP forward '{}' is listening at {} for {}", config.name, config.listen, config.forward_to);
+        Ok(Arc::new(Self { config: config.clone(), listener: StdMutex::new(Some(listener)), stats, accept }))
+    }
+
+    /// Stop accepting connections, the listener is returned unless it was taken already
+    pub fn stop(&self) -> Option<Arc<TcpListener>> {
+        self.accept.abort();
+        self.listener.lock().unwrap().take()
+    }
+
+    pub fn config(&self) -> &ForwardConfig {
+        &self.config
+    }
+
+    pub fn listens_on(&self, address: &str) -> bool {
+        self.config.listen == address
+    }
+
+    /// Whether this forward can be kept for `config`
+    pub fn serves(&self, config: &ForwardConfig) -> bool {
+        self.config == *config
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+impl Drop for TcpForward {
+    fn drop(&mut self) {
+        // connections already forwarded go on until either side closes them
+        self.accept.abort();
+    }
+}
+
+/// Bind without waiting, a reload sets up forwards while building the context
+fn bind(address: &str) -> std::io::Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+async fn accept(listener: Arc<TcpListener>, config: ForwardConfig, stats: Arc<Stats>) {
+    loop {
+        let (client, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // like running out of file descriptors, give other connections a moment to close
+                tracing::warn!("Failed to accept a connection for TCP forward '{}': {err}", config.name);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        if config.max_connections > 0 && stats.active.load(Ordering::Relaxed) >= config.max_connections {
+            stats.refused.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Refused connection from {peer} to TCP forward '{}', it has {} connections already",
+                config.name,
+                config.max_connections);
+            continue;
+        }
+        stats.active.fetch_add(1, Ordering::Relaxed);
+        stats.accepted.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(forward(client, peer, config.clone(), stats.clone()));
+    }
+}
+
+async fn forward(client: TcpStream, peer: SocketAddr, config: ForwardConfig, stats: Arc<Stats>) {
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&config.forward_to)).await {
+        Ok(Ok(backend)) => {
+            tracing::debug!("Forwarding connection from {peer} to {} for TCP forward '{}'", config.forward_to, config.name);
+            let _ = client.set_nodelay(true);
+            let _ = backend.set_nodelay(true);
+            let idle_timeout = Some(Duration::from_millis(config.idle_timeout)).filter(|timeout| !timeout.is_zero());
+            match splice(client, backend, idle_timeout, &stats).await {
+                Ok(()) => tracing::debug!("Connection from {peer} to TCP forward '{}' closed", config.name),
+                Err(err) => tracing::debug!("Connection from {peer} to TCP forward '{}' closed: {err}", config.name),
+            }
+        }
+        Ok(Err(err)) => {
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Failed to connect to {} for TCP forward '{}': {err}", config.forward_to, config.name);
+        }
+        Err(_) => {
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Timed out connecting to {} for TCP forward '{}'", config.forward_to, config.name);
+        }
+    }
+    stats.active.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Copy the data of both sides to the other one until both are done sending, either fails,
+/// or neither sent anything for `idle_timeout`
+async fn splice(client: TcpStream, backend: TcpStream, idle_timeout: Option<Duration>, stats: &Stats) -> std::io::Result<()> {
+    let (mut client_read, mut client_write) = client.into_split();
+    let (mut backend_read, mut backend_write) = backend.into_split();
+    let last_active = StdMutex::new(Instant::now());
+    let upstream = copy(&mut client_read, &mut backend_write, &stats.sent, &last_active);
+    let downstream = copy(&mut backend_read, &mut client_write, &stats.received, &last_active);
+    let idle = async {
+        let Some(idle_timeout) = idle_timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let deadline = *last_active.lock().unwrap() + idle_timeout;
+            if deadline <= Instant::now() {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    };
+    select! {
+        result = async { tokio::try_join!(upstream, downstream) } => result.map(|_| ()),
+        _ = idle => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "idle timeout")),
+    }
+}
+
+/// Copy until `from` ends, then end `to` as well so the other side sees it
+async fn copy<R, W>(from: &mut R, to: &mut W, counter: &AtomicU64, last_active: &StdMutex<Instant>) -> std::io::Result<()>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let size = from.read(&mut buffer).await?;
+        if size == 0 {
+            return to.shutdown().await;
+        }
+        to.write_all(&buffer[..size]).await?;
+        counter.fetch_add(size as u64, Ordering::Relaxed);
+        *last_active.lock().unwrap() = Instant::now();
+    }
+}
+
+/// Sessions and traffic of a UDP forward, kept when a reload changes its settings
+#[derive(Default)]
+pub struct UdpStats {
+    opened: AtomicU64,
+    /// Datagrams of new clients while `max_sessions` were open
+    refused: AtomicU64,
+    /// Sessions that could not get a socket to the backend
+    failed: AtomicU64,
+    /// Datagrams larger than `datagram_size`, or that could not be sent on
+    dropped: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+/// One client of a UDP forward and its socket connected to the backend
+pub struct Session {
+    backend: UdpSocket,
+    last_active: StdMutex<Instant>,
+    datagrams_sent: AtomicU64,
+    sent: AtomicU64,
+    datagrams_received: AtomicU64,
+    received: AtomicU64,
+    /// Relays the replies of the backend and expires the session
+    task: OnceLock<AbortHandle>,
+}
+
+impl Session {
+    pub fn describe(&self) -> String {
+        format!("{} datagrams ({}) sent, {} datagrams ({}) received, idle for {}s",
+            self.datagrams_sent.load(Ordering::Relaxed),
+            format_bytes(self.sent.load(Ordering::Relaxed)),
+            self.datagrams_received.load(Ordering::Relaxed),
+            format_bytes(self.received.load(Ordering::Relaxed)),
+            self.last_active.lock().unwrap().elapsed().as_secs())
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+}
+
+/// The socket of a UDP forward with its sessions, handed to the next forward on the same address
+pub struct Relay {
+    socket: UdpSocket,
+    sessions: StdMutex<HashMap<SocketAddr, Arc<Session>>>,
+    stats: UdpStats,
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        // sessions end with the forward, which closes their sockets to the backend
+        for session in self.sessions.get_mut().unwrap().values() {
+            if let Some(task) = session.task.get() {
+                task.abort();
+            }
+        }
+    }
+}
+
+/// Receives the datagrams of a `[[udp_forward]]` and relays each client through its own session
+pub struct UdpForward {
+    config: UdpForwardConfig,
+    /// Taken when the forward stops, like the listener of a TCP forward
+    relay: StdMutex<Option<Arc<Relay>>>,
+    receive: AbortHandle,
+}
+
+impl UdpForward {
+    /// Start receiving datagrams for `config`. The socket, sessions and counters of `previous`
+    /// are taken over when it listens on the same address, open sessions keep their backend then
+    pub fn start(config: &UdpForwardConfig, previous: Option<&UdpForward>) -> std::io::Result<Arc<Self>> {
+        let unchanged = previous.is_some_and(|previous| previous.serves(config));
+        let relay = match previous.filter(|previous| previous.listens_on(&config.listen)).and_then(UdpForward::stop) {
+            Some(relay) => relay,
+            None => Arc::new(Relay {
+                socket: bind_udp(&config.listen, config.socket_buffer)?,
+                sessions: StdMutex::new(HashMap::new()),
+                stats: UdpStats::default(),
+            }),
+        };
+        let receive = tokio::spawn(receive(relay.clone(), config.clone())).abort_handle();
+        if !unchanged {
+            tracing::info!("UDP forward '{}' is listening at {} for {}", config.name, config.listen, config.forward_to);
+        }
+        Ok(Arc::new(Self { config: config.clone(), relay: StdMutex::new(Some(relay)), receive }))
+    }
+
+    /// Stop receiving datagrams, the relay is returned unless it was taken already
+    pub fn stop(&self) -> Option<Arc<Relay>> {
+        self.receive.abort();
+        self.relay.lock().unwrap().take()
+    }
+
+    pub fn config(&self) -> &UdpForwardConfig {
+        &self.config
+    }
+
+    pub fn listens_on(&self, address: &str) -> bool {
+        self.config.listen == address
+    }
+
+    /// Whether this forward can be kept for `config`
+    pub fn serves(&self, config: &UdpForwardConfig) -> bool {
+        self.config == *config
+    }
+
+    /// The counters of the forward and every session by client address, None once stopped
+    pub fn describe(&self) -> Option<(String, Vec<(SocketAddr, String)>)> {
+        let relay = self.relay.lock().unwrap().clone()?;
+        let stats = &relay.stats;
+        let mut sessions = relay.sessions.lock().unwrap().iter()
+            .map(|(client, session)| (*client, session.describe()))
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|(client, _)| *client);
+        let summary = format!("{} sessions, {} opened, {} refused, {} failed, {} dropped, {} sent, {} received",
+            sessions.len(),
+            stats.opened.load(Ordering::Relaxed),
+            stats.refused.load(Ordering::Relaxed),
+            stats.failed.load(Ordering::Relaxed),
+            stats.dropped.load(Ordering::Relaxed),
+            format_bytes(stats.sent.load(Ordering::Relaxed)),
+            format_bytes(stats.received.load(Ordering::Relaxed)));
+        Some((summary, sessions))
+    }
+}
+
+impl Drop for UdpForward {
+    fn drop(&mut self) {
+        self.receive.abort();
+    }
+}
+
+fn bind_udp(address: &str, buffer: usize) -> std::io::Result<UdpSocket> {
+    let socket = std::net::UdpSocket::bind(address)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    set_buffers(&socket, buffer);
+    Ok(socket)
+}
+
+fn set_buffers(socket: &UdpSocket, buffer: usize) {
+    if buffer == 0 {
+        return;
+    }
+    let socket = SockRef::from(socket);
+    if let Err(err) = socket.set_recv_buffer_size(buffer).and_then(|_| socket.set_send_buffer_size(buffer)) {
+        tracing::warn!("Failed to set the buffers of a UDP socket to {buffer} bytes: {err}");
+    }
+}
+
+/// Relay the datagrams of clients. Only `recv_from` and sends are awaited in the loop, so a new
+/// client never holds up the datagrams of the others
+async fn receive(relay: Arc<Relay>, config: UdpForwardConfig) {
+    let backend = resolve(&config).await;
+    // one byte more, a datagram that fills the buffer was cut
+    let mut buffer = vec![0; config.datagram_size + 1];
+    loop {
+        let (size, client) = match relay.socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(err) => {
+                tracing::debug!("Failed to receive a datagram for UDP forward '{}': {err}", config.name);
+                continue;
+            }
+        };
+        if size > config.datagram_size {
+            relay.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!("Dropped a datagram from {client} to UDP forward '{}', it is larger than {} bytes", config.name, config.datagram_size);
+            continue;
+        }
+        let Some(session) = session(&relay, client, backend, &config) else {
+            continue;
+        };
+        match session.backend.send(&buffer[..size]).await {
+            Ok(_) => {
+                session.touch();
+                session.datagrams_sent.fetch_add(1, Ordering::Relaxed);
+                session.sent.fetch_add(size as u64, Ordering::Relaxed);
+                relay.stats.sent.fetch_add(size as u64, Ordering::Relaxed);
+            }
+            Err(err) => {
+                relay.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!("Failed to send a datagram from {client} to {} for UDP forward '{}': {err}", config.forward_to, config.name);
+            }
+        }
+    }
+}
+
+/// The address of the backend, looked up once when the forward starts, sessions are opened
+/// without waiting for DNS then. Datagrams are not received until the lookup succeeds
+async fn resolve(config: &UdpForwardConfig) -> SocketAddr {
+    loop {
+        match tokio::net::lookup_host(&config.forward_to).await.map(|mut found| found.next()) {
+            Ok(Some(backend)) => return backend,
+            Ok(None) => tracing::warn!("No address found for {} of UDP forward '{}'", config.forward_to, config.name),
+            Err(err) => tracing::warn!("Failed to look up {} for UDP forward '{}': {err}", config.forward_to, config.name),
+        }
+        tokio::time::sleep(CONNECT_TIMEOUT).await;
+    }
+}
+
+/// The session of `client`, opened on its first datagram
+fn session(relay: &Arc<Relay>, client: SocketAddr, backend: SocketAddr, config: &UdpForwardConfig) -> Option<Arc<Session>> {
+    let open = {
+        let sessions = relay.sessions.lock().unwrap();
+        if let Some(session) = sessions.get(&client) {
+            return Some(session.clone());
+        }
+        sessions.len()
+    };
+    if config.max_sessions > 0 && open >= config.max_sessions {
+        relay.stats.refused.fetch_add(1, Ordering::Relaxed);
+        tracing::debug!("Dropped a datagram from {client} to UDP forward '{}', it has {} sessions already", config.name, config.max_sessions);
+        return None;
+    }
+    let backend = match connect_udp(backend, config.socket_buffer) {
+        Ok(backend) => backend,
+        Err(err) => {
+            relay.stats.failed.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Failed to open a socket to {} for UDP forward '{}': {err}", config.forward_to, config.name);
+            return None;
+        }
+    };
+    let session = Arc::new(Session {
+        backend,
+        last_active: StdMutex::new(Instant::now()),
+        datagrams_sent: AtomicU64::new(0),
+        sent: AtomicU64::new(0),
+        datagrams_received: AtomicU64::new(0),
+        received: AtomicU64::new(0),
+        task: OnceLock::new(),
+    });
+    relay.sessions.lock().unwrap().insert(client, session.clone());
+    let task = tokio::spawn(reply(session.clone(), client, Arc::downgrade(relay), config.clone()));
+    let _ = session.task.set(task.abort_handle());
+    relay.stats.opened.fetch_add(1, Ordering::Relaxed);
+    tracing::debug!("Opened a session for {client} to {} for UDP forward '{}'", config.forward_to, config.name);
+    Some(session)
+}
+
+/// A socket connected to `backend`, binding and connecting a UDP socket does not block
+fn connect_udp(backend: SocketAddr, buffer: usize) -> std::io::Result<UdpSocket> {
+    let local: SocketAddr = if backend.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0; 16], 0).into() };
+    let socket = std::net::UdpSocket::bind(local)?;
+    socket.connect(backend)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    set_buffers(&socket, buffer);
+    Ok(socket)
+}
+
+/// Send what the backend replies to `client` until neither sent a datagram for `idle_timeout`
+async fn reply(session: Arc<Session>, client: SocketAddr, relay: Weak<Relay>, config: UdpForwardConfig) {
+    let idle_timeout = Duration::from_millis(config.idle_timeout);
+    let mut buffer = vec![0; config.datagram_size + 1];
+    loop {
+        let deadline = *session.last_active.lock().unwrap() + idle_timeout;
+        if deadline <= Instant::now() {
+            break;
+        }
+        let size = match tokio::time::timeout_at(deadline, session.backend.recv(&mut buffer)).await {
+            Ok(Ok(size)) => size,
+            // like the backend port being closed, the session goes on until it expires
+            Ok(Err(err)) => {
+                tracing::debug!("Failed to receive a datagram from {} for UDP forward '{}': {err}", config.forward_to, config.name);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let Some(relay) = relay.upgrade() else {
+            return;
+        };
+        if size > config.datagram_size {
+            relay.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::debug!("Dropped a datagram from {} to {client} for UDP forward '{}', it is larger than {} bytes", config.forward_to, config.name, config.datagram_size);
+            continue;
+        }
+        match relay.socket.send_to(&buffer[..size], client).await {
+            Ok(_) => {
+                session.touch();
+                session.datagrams_received.fetch_add(1, Ordering::Relaxed);
+                session.received.fetch_add(size as u64, Ordering::Relaxed);
+                relay.stats.received.fetch_add(size as u64, Ordering::Relaxed);
+            }
+            Err(err) => {
+                relay.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                tracing::debug!("Failed to send a datagram to {client} for UDP forward '{}': {err}", config.name);
+            }
+        }
+    }
+    if let Some(relay) = relay.upgrade() {
+        let mut sessions = relay.sessions.lock().unwrap();
+        if sessions.get(&client).is_some_and(|open| Arc::ptr_eq(open, &session)) {
+            sessions.remove(&client);
+        }
+    }
+    tracing::debug!("Session of {client} for UDP forward '{}' expired", config.name);
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
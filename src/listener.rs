@@ -0,0 +1,116 @@
+use std::io;
+use std::net::SocketAddr;
+use axum::{Router, extract::Request};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener
+};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use crate::config::ListenerConfig;
+use crate::tls::ClientCert;
+
+/// A bound listener of the server, on a TCP address or a Unix socket
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, TlsAcceptor),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, std::path::PathBuf),
+}
+
+impl Listener {
+    /// Bind `config`, TCP listeners serve HTTPS when given a `tls` acceptor
+    pub async fn bind(config: &ListenerConfig, tls: Option<TlsAcceptor>) -> io::Result<Self> {
+        if let Some(path) = config.address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                // a socket file left by a previous run would make binding fail
+                if std::fs::symlink_metadata(path).is_ok_and(|meta| {
+                    use std::os::unix::fs::FileTypeExt;
+                    meta.file_type().is_socket()
+                }) {
+                    std::fs::remove_file(path)?;
+                }
+                return Ok(Listener::Unix(tokio::net::UnixListener::bind(path)?, path.into()));
+            }
+            #[cfg(not(unix))]
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unix socket `{path}` is not supported on this platform")));
+        }
+        let listener = TcpListener::bind(config.address.as_str()).await?;
+        Ok(match tls {
+            Some(acceptor) => Listener::Tls(listener, acceptor),
+            None => Listener::Tcp(listener),
+        })
+    }
+
+    /// The address actually bound, with the port resolved
+    pub fn local_address(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr()
+                .map_or_else(|_| String::from("?"), |address: SocketAddr| format!("http://{address}")),
+            Listener::Tls(listener, _) => listener.local_addr()
+                .map_or_else(|_| String::from("?"), |address: SocketAddr| format!("https://{address}")),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    /// Accept connections forever and serve them with `app`
+    pub async fn serve(self, app: Router) {
+        loop {
+            let accepted = match &self {
+                Listener::Tcp(listener) => listener.accept().await
+                    .map(|(stream, _)| tokio::spawn(serve_connection(stream, app.clone(), None))),
+                Listener::Tls(listener, acceptor) => listener.accept().await
+                    .map(|(stream, address)| {
+                        let (acceptor, app) = (acceptor.clone(), app.clone());
+                        // the handshake runs in the connection task, a slow client does not block accepting
+                        tokio::spawn(async move {
+                            match acceptor.accept(stream).await {
+                                Ok(stream) => {
+                                    let client_cert = stream.get_ref().1.peer_certificates()
+                                        .and_then(|certs| certs.first())
+                                        .and_then(ClientCert::from_der);
+                                    serve_connection(stream, app, client_cert).await
+                                }
+                                Err(err) => tracing::debug!("TLS handshake with {address} failed: {err}"),
+                            }
+                        })
+                    }),
+                #[cfg(unix)]
+                Listener::Unix(listener, _) => listener.accept().await
+                    .map(|(stream, _)| tokio::spawn(serve_connection(stream, app.clone(), None))),
+            };
+            if let Err(err) = accepted {
+                // mostly running out of file descriptors, wait instead of spinning
+                tracing::error!("Failed to accept connection on {}: {err}", self.local_address());
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+/// Serve the requests of one connection, tagged with the verified certificate of the client
+async fn serve_connection<S>(stream: S, app: Router, client_cert: Option<ClientCert>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = TowerToHyperService::new(app.map_request(move |req: Request<Incoming>| {
+        let mut req = req.map(axum::body::Body::new);
+        if let Some(client_cert) = &client_cert {
+            req.extensions_mut().insert(client_cert.clone());
+        }
+        req
+    }));
+    if let Err(err) = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+        .await {
+        tracing::debug!("Connection closed with error: {err}");
+    }
+}
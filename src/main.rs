@@ -1,125 +1,359 @@
 mod utils;
+mod cli;
 mod config;
 mod services;
 mod commands;
+mod reload;
+mod listener;
+mod tls;
+mod cert;
+mod multiplex;
+mod pool;
+mod supervisor;
+mod forward;
 
 use std::sync::Arc;
+use std::collections::HashMap;
 use anyhow::{anyhow, Result};
 use tracing::Level;
 use axum::{
     Router,
-    ServiceExt,
-    body::Body,
-    extract::Request
+    body::Body
 };
-use tokio::{sync::Mutex, net::{TcpListener, TcpStream}};
+use tokio::{net::TcpStream, task::JoinSet};
 use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
 use tokio_tungstenite::{WebSocketStream, MaybeTlsStream};
+use clap::Parser;
 use rustyline_async::Readline;
-use crate::config::SERVER_CONFIG;
+use crate::config::{SERVER_CONFIG, ServerConfig, ListenerConfig};
+use crate::listener::Listener;
+use crate::forward::{TcpForward, UdpForward};
+use crate::multiplex::Multiplexer;
+use crate::pool::Pool;
 
 type HttpClient = hyper_util::client::legacy::Client<HttpConnector, Body>;
 
 #[derive(Clone)]
 pub struct ServerContext {
-    pub ws_proxy: Option<Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>>,
-    pub tcp_proxy: Option<Arc<Mutex<TcpStream>>>,
+    pub ws_proxy: HashMap<String, Arc<Pool<WebSocketStream<MaybeTlsStream<TcpStream>>>>>,
+    /// Websocket proxies with an envelope, their requests share the connection concurrently
+    pub ws_multiplexed: HashMap<String, Arc<Multiplexer>>,
+    pub tcp_proxy: HashMap<String, Arc<Pool<TcpStream>>>,
     pub reverse_proxy: Option<HttpClient>,
+    pub tcp_forward: HashMap<String, Arc<TcpForward>>,
+    pub udp_forward: HashMap<String, Arc<UdpForward>>,
+}
+
+impl ServerContext {
+    /// Set up the connections to all configured backends, they connect in the background. The
+    /// connections of `previous` are reused for the proxies whose target and pool settings did not change,
+    /// and its forwards on the same addresses keep their listeners while the others stop
+    pub fn new(config: &ServerConfig, previous: Option<(&ServerConfig, &ServerContext)>) -> Self {
+        let mut ws_proxy = HashMap::new();
+        let mut ws_multiplexed = HashMap::new();
+        for proxy in &config.websocket_proxy {
+            if let Some(envelope) = &proxy.envelope {
+                let reused = previous.and_then(|(_, context)| context.ws_multiplexed.get(&proxy.name))
+                    .filter(|multiplexer| multiplexer.serves(proxy))
+                    .cloned();
+                let multiplexer = reused.unwrap_or_else(|| Multiplexer::new(proxy, envelope.clone()));
+                ws_multiplexed.insert(proxy.name.clone(), multiplexer);
+                continue;
+            }
+            let reused = previous.and_then(|(_, context)| context.ws_proxy.get(&proxy.name))
+                .filter(|pool| pool.serves(proxy))
+                .cloned();
+            ws_proxy.insert(proxy.name.clone(), reused.unwrap_or_else(|| Pool::new(proxy)));
+        }
+        let mut tcp_proxy = HashMap::new();
+        for proxy in &config.tcp_proxy {
+            let reused = previous.and_then(|(_, context)| context.tcp_proxy.get(&proxy.name))
+                .filter(|pool| pool.serves(proxy))
+                .cloned();
+            tcp_proxy.insert(proxy.name.clone(), reused.unwrap_or_else(|| Pool::new(proxy)));
+        }
+        let reverse_proxy = if !config.reverse_proxy.is_empty() {
+            match previous.and_then(|(_, context)| context.reverse_proxy.clone()) {
+                Some(client) => Some(client),
+                None => {
+                    let client: HttpClient = hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
+                        .build(HttpConnector::new());
+                    Some(client)
+                }
+            }
+        } else { None };
+        let mut tcp_forward = HashMap::new();
+        for forward in &config.tcp_forward {
+            // matched by address, the listener is taken over even if the forward was renamed
+            let listening = previous.and_then(|(_, context)| context.tcp_forward.values().find(|previous| previous.listens_on(&forward.listen)));
+            if let Some(listening) = listening.filter(|listening| listening.serves(forward)) {
+                tcp_forward.insert(forward.name.clone(), listening.clone());
+                continue;
+            }
+            match TcpForward::start(forward, listening.map(Arc::as_ref)) {
+                Ok(started) => {
+                    tcp_forward.insert(forward.name.clone(), started);
+                }
+                Err(err) => tracing::error!("Failed to listen at {} for TCP forward '{}': {err}", forward.listen, forward.name),
+            }
+        }
+        for removed in previous.iter().flat_map(|(_, context)| context.tcp_forward.values()) {
+            if !tcp_forward.values().any(|kept| Arc::ptr_eq(kept, removed)) && removed.stop().is_some() {
+                tracing::info!("TCP forward '{}' stopped listening at {}", removed.config().name, removed.config().listen);
+            }
+        }
+        let mut udp_forward = HashMap::new();
+        for forward in &config.udp_forward {
+            // started again even when unchanged, so `forward_to` is looked up again
+            let listening = previous.and_then(|(_, context)| context.udp_forward.values().find(|previous| previous.listens_on(&forward.listen)));
+            match UdpForward::start(forward, listening.map(Arc::as_ref)) {
+                Ok(started) => {
+                    udp_forward.insert(forward.name.clone(), started);
+                }
+                Err(err) => tracing::error!("Failed to listen at {} for UDP forward '{}': {err}", forward.listen, forward.name),
+            }
+        }
+        for removed in previous.iter().flat_map(|(_, context)| context.udp_forward.values()) {
+            if !udp_forward.values().any(|kept| Arc::ptr_eq(kept, removed)) && removed.stop().is_some() {
+                tracing::info!("UDP forward '{}' stopped listening at {}", removed.config().name, removed.config().listen);
+            }
+        }
+        Self {
+            ws_proxy,
+            ws_multiplexed,
+            tcp_proxy,
+            reverse_proxy,
+            tcp_forward,
+            udp_forward,
+        }
+    }
 }
 
 // #[tokio::main(flavor = "multi_thread", worker_threads = 16)]
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = cli::Cli::parse();
+    if cli.print_default_config {
+        print!("{}", config::default_config_toml());
+        return Ok(());
+    }
+    config::configure(cli.config.clone(), cli.overrides());
+    if cli.migrate_config {
+        migrate_config();
+    }
+    // only validate the config file if asked to
+    if cli.check_config {
+        check_config();
+    }
     // init config
-    config::init_config();
-    let (ws_proxy_config, tcp_proxy_config, reverse_proxy_config, port) = {
-        let config = match SERVER_CONFIG.read() {
-            Ok(config) => config,
-            Err(poison_error) => {
-                return Err(anyhow!("Failed to read server config: {poison_error}"));
-            }
-        };
-        (
-            config.websocket_proxy.clone(),
-            config.tcp_proxy.clone(),
-            config.reverse_proxy.clone(),
-            config.server.port,
-        )
+    let warnings = match config::init_config() {
+        Ok(warnings) => warnings,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(cli::Command::Cert { action: cli::CertCommand::Generate { names, dir } }) = &cli.command {
+        generate_cert(dir, names);
+    }
+    if cli.dry_run {
+        dry_run(&warnings);
+    }
+    let server_config = match SERVER_CONFIG.read() {
+        Ok(config) => config.clone(),
+        Err(poison_error) => {
+            return Err(anyhow!("Failed to read server config: {poison_error}"));
+        }
     };
     // show banner
     utils::banner();
     // init tracing and command manager
-    let rl = Readline::new(String::from(">> ")).ok();
+    let rl = if cli.no_repl { None } else { Readline::new(String::from(">> ")).ok() };
     let guard = utils::init_tracing(rl.as_ref().map(|(_, out)| out.clone()));
-    let mut command_mgr = commands::CommandManager::new();
+    let command_mgr = commands::CommandManager::new();
     if let Some((rl, out)) = rl {
         command_mgr.run(rl, out, guard);
     } else {
-        // if create readline failed, use this thread to ensure the completeness of file log
+        if !cli.no_repl {
+            tracing::warn!("Fail to create Readline, console log with std::io::Stdout...");
+        }
+        // without readline, use this thread to ensure the completeness of file log
         utils::wait_file_log_guard(guard);
     }
     // show info
     utils::info();
+    for warning in warnings {
+        tracing::warn!("{warning}");
+    }
     // start tracing span
     let span = tracing::span!(Level::DEBUG, "main");
     let _ = span.enter();
 
-    // init server context
-    let ws_proxy = if let Some(config) = ws_proxy_config {
-        utils::make_websocket_stream(&config).await
-    } else { None };
-    let tcp_proxy = if let Some(config) = tcp_proxy_config {
-        utils::make_tcp_stream(&config).await
-    } else { None };
-    let reverse_proxy = if reverse_proxy_config.is_some() {
-        let client: HttpClient = hyper_util::client::legacy::Client::<(), ()>::builder(TokioExecutor::new())
-                .build(HttpConnector::new());
-        Some(client)
-    } else { None };
-    let state = Arc::new(ServerContext {
-        ws_proxy,
-        tcp_proxy,
-        reverse_proxy,
-    });
-    // using server context also in command manager
-    command_mgr.set_context(state.clone()).await;
-
-    // init app
-    let app = create_router(state.clone());
-    let app = app.with_state(state);
+    // init server context and app, commands read the context installed here
+    let state = Arc::new(ServerContext::new(&server_config, None));
+    reload::install(state);
+    reload::spawn_triggers();
 
-    // init server
-    let addr = format!("0.0.0.0:{}", port);
-    let server = match TcpListener::bind(&addr).await {
-        Ok(server) => server,
+    // init server, bind every listener before serving any of them
+    let acceptor = match server_config.server.tls.as_ref().map(tls::acceptor).transpose() {
+        Ok(acceptor) => acceptor,
         Err(err) => {
-            let error_msg = format!("Failed to bind TCP listener: {}", err);
+            let error_msg = format!("Failed to load TLS certificate: {err}");
             tracing::error!("{error_msg}");
             return Err(anyhow!("{error_msg}"));
         }
     };
-
-    tracing::info!("Server is listening at {addr}");
-    axum::serve(server, ServiceExt::<Request>::into_make_service(app)).await?;
+    let mut listeners = Vec::new();
+    for config in server_config.server.listeners() {
+        let tls = acceptor.clone().filter(|_| server_config.server.is_tls(&config));
+        match Listener::bind(&config, tls).await {
+            Ok(listener) => listeners.push((listener, config)),
+            Err(err) => {
+                let error_msg = format!("Failed to bind listener {}: {}", config.address, err);
+                tracing::error!("{error_msg}");
+                return Err(anyhow!("{error_msg}"));
+            }
+        }
+    }
+    let mut servers = JoinSet::new();
+    if let Some(address) = server_config.server.tls.as_ref().and_then(|tls| tls.redirect_http.clone()) {
+        let config = ListenerConfig { address, services: None, plain: true };
+        match Listener::bind(&config, None).await {
+            Ok(listener) => {
+                tracing::info!("Redirecting {} to HTTPS", listener.local_address());
+                servers.spawn(listener.serve(services::redirect::router(server_config.server.https_port())));
+            }
+            Err(err) => {
+                let error_msg = format!("Failed to bind HTTP redirect listener {}: {}", config.address, err);
+                tracing::error!("{error_msg}");
+                return Err(anyhow!("{error_msg}"));
+            }
+        }
+    }
+    for (index, (listener, config)) in listeners.into_iter().enumerate() {
+        match &config.services {
+            Some(services) => tracing::info!("Server is listening at {} for {}", listener.local_address(), services.join(", ")),
+            None => tracing::info!("Server is listening at {}", listener.local_address()),
+        }
+        servers.spawn(listener.serve(reload::service(index)));
+    }
+    while servers.join_next().await.is_some() {}
 
     Ok(())
 }
 
-fn create_router(context: Arc<ServerContext>) -> Router<Arc<ServerContext>> {
-    let mut router = Router::new();
-    // setup all routes
-    if context.ws_proxy.is_some() {
-        router = services::websocket_proxy::setup_routes(router);
+fn migrate_config() {
+    let config_file = config::config_file();
+    match config::migrate_config_file(config_file) {
+        Ok(migrations) if migrations.is_empty() => {
+            println!("{config_file} is already at version {}", config::CONFIG_VERSION);
+        }
+        Ok(migrations) => {
+            for migration in migrations {
+                println!("Migrated {config_file}: {migration}");
+            }
+            println!("The previous version was saved to {config_file}.bak");
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn check_config() -> ! {
+    let config_file = config::config_file();
+    match config::read_config(config_file) {
+        Ok(loaded) => {
+            for warning in loaded.warnings {
+                println!("{warning}");
+            }
+            println!("{config_file} is valid");
+            std::process::exit(0);
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn generate_cert(directory: &str, names: &[String]) -> ! {
+    let names = if names.is_empty() {
+        cert::configured_names(&SERVER_CONFIG.read().unwrap())
+    } else {
+        names.to_vec()
+    };
+    let generated = match cert::generate(directory, names) {
+        Ok(generated) => generated,
+        Err(err) => {
+            eprintln!("Failed to generate the certificate: {err}");
+            std::process::exit(1);
+        }
+    };
+    println!("{}", generated.describe());
+    let config_file = config::config_file();
+    match config::save_certificate(&generated.cert, &generated.key) {
+        Ok(backup) => {
+            if let Some(backup) = backup {
+                println!("Migrated {config_file} to version {}, the previous version was saved to {backup}", config::CONFIG_VERSION);
+            }
+            println!("Serving HTTPS with it, see [server.tls] in {config_file}");
+        }
+        Err(err) => {
+            eprintln!("Failed to save {config_file}: {err}");
+            std::process::exit(1);
+        }
     }
-    if context.tcp_proxy.is_some() {
-        router = services::tcp_proxy::setup_routes(router);
+    std::process::exit(0);
+}
+
+fn dry_run(warnings: &[config::Issue]) -> ! {
+    for warning in warnings {
+        println!("{warning}");
     }
+    let config = SERVER_CONFIG.read().unwrap();
+    for listener in config.server.listeners() {
+        let routes = services::route_table(&config, &listener);
+        let width = routes.iter().map(|route| route.path.len()).max().unwrap_or(0).max("PATH".len());
+        if config.server.is_tls(&listener) {
+            println!("\nListener {} (HTTPS)", listener.address);
+        } else {
+            println!("\nListener {}", listener.address);
+        }
+        println!("{:<6} {:<width$} SERVICE", "METHOD", "PATH");
+        for route in routes {
+            println!("{:<6} {:<width$} {}", route.method, route.path, route.service);
+        }
+    }
+    if let Some(address) = config.server.tls.as_ref().and_then(|tls| tls.redirect_http.as_ref()) {
+        println!("\nListener {address} redirects to HTTPS");
+    }
+    for forward in &config.tcp_forward {
+        println!("\nListener {} forwards TCP to {} (tcp_forward '{}')", forward.listen, forward.forward_to, forward.name);
+    }
+    for forward in &config.udp_forward {
+        println!("\nListener {} forwards UDP to {} (udp_forward '{}')", forward.listen, forward.forward_to, forward.name);
+    }
+    std::process::exit(0);
+}
+
+fn create_router(context: Arc<ServerContext>, listener: &ListenerConfig) -> Router<Arc<ServerContext>> {
+    let mut router = Router::new();
+    tracing::info!("Setting up routes for listener {}", listener.address);
+    // setup the routes of this listener. Proxies are mounted right away, also while their backend
+    // is down: requests wait for a connection of the pool or fail by the state of its supervisor
+    router = services::websocket_proxy::setup_routes(router, &context, listener);
+    router = services::tcp_proxy::setup_routes(router, &context, listener);
     if context.reverse_proxy.is_some() {
-        router = services::reverse_proxy::setup_routes(router);
+        router = services::reverse_proxy::setup_routes(router, listener);
+    }
+    if listener.serves("api", None) {
+        router = services::api::setup_routes(router);
     }
-    router = services::api::setup_routes(router);
-    if SERVER_CONFIG.read().unwrap().web.is_some() {
+    if SERVER_CONFIG.read().unwrap().web.is_some() && listener.serves("web", None) {
         router = services::web::setup_routes(router);
     }
     services::default::setup_routes(router)
+        .layer(axum::middleware::from_fn(services::client_auth::check))
 }
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use axum::http::StatusCode;
+use futures_util::{SinkExt, StreamExt, stream::SplitSink};
+use tokio::{
+    net::TcpStream,
+    sync::{Mutex, oneshot},
+    task::JoinHandle,
+    time::{Duration, Instant}
+};
+use tokio_tungstenite::{tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
+use crate::config::{EnvelopeConfig, EnvelopeFormat, HandshakeConfig, ProxyConfig};
+use crate::services::events::{Events, Notification};
+use crate::supervisor::{self, Supervisor, CHECK_INTERVAL, CONNECT_TIMEOUT, PONG_TIMEOUT};
+use crate::utils::create_websocket_stream;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Callers waiting for their response, with the generation of the connection the request went out on
+type Pending = HashMap<u32, (u64, oneshot::Sender<Message>)>;
+
+impl EnvelopeConfig {
+    /// Tag `body` with `id`, JSON requests must be objects
+    fn seal(&self, id: u32, body: Vec<u8>) -> Result<Message, String> {
+        match self.format {
+            EnvelopeFormat::Json => {
+                let mut object = match serde_json::from_slice::<serde_json::Value>(&body) {
+                    Ok(serde_json::Value::Object(object)) => object,
+                    _ => return Err(String::from("the request body must be a JSON object")),
+                };
+                object.insert(self.field.clone(), serde_json::Value::from(id));
+                Ok(Message::Text(serde_json::Value::Object(object).to_string()))
+            }
+            EnvelopeFormat::Binary => {
+                let mut message = id.to_be_bytes().to_vec();
+                message.extend_from_slice(&body);
+                Ok(Message::Binary(message))
+            }
+        }
+    }
+
+    /// The ID of a response and the response without it
+    fn open(&self, message: Message) -> Option<(u32, Message)> {
+        match (self.format, message) {
+            (EnvelopeFormat::Json, Message::Text(text)) => {
+                let mut object = match serde_json::from_str::<serde_json::Value>(&text) {
+                    Ok(serde_json::Value::Object(object)) => object,
+                    _ => return None,
+                };
+                let id = object.remove(&self.field)?.as_u64().and_then(|id| u32::try_from(id).ok())?;
+                Some((id, Message::Text(serde_json::Value::Object(object).to_string())))
+            }
+            (EnvelopeFormat::Binary, Message::Binary(mut data)) if data.len() >= 4 => {
+                let payload = data.split_off(4);
+                Some((u32::from_be_bytes(data.try_into().ok()?), Message::Binary(payload)))
+            }
+            _ => None,
+        }
+    }
+}
+
+struct Connection {
+    sink: SplitSink<WsStream, Message>,
+    generation: u64,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// One connection to a websocket backend shared by concurrent requests, requests carry an ID
+/// and a background reader hands every response to the request with the same ID
+pub struct Multiplexer {
+    name: String,
+    forward_to: String,
+    envelope: EnvelopeConfig,
+    handshake: Option<HandshakeConfig>,
+    heartbeat: u64,
+    max_backoff: u64,
+    supervisor: Supervisor,
+    events: Events,
+    connection: Mutex<Option<Connection>>,
+    /// When the backend last sent anything, a ping it does not answer means the connection is dead
+    last_seen: StdMutex<Instant>,
+    pending: StdMutex<Pending>,
+    next_id: AtomicU32,
+    generations: AtomicU64,
+}
+
+impl Multiplexer {
+    /// A multiplexer for `config`, a background task connects to the backend and keeps it connected
+    pub fn new(config: &ProxyConfig, envelope: EnvelopeConfig) -> Arc<Self> {
+        let multiplexer = Arc::new(Self {
+            name: config.name.clone(),
+            forward_to: config.forward_to.clone(),
+            envelope,
+            handshake: config.handshake.clone(),
+            heartbeat: config.heartbeat,
+            max_backoff: config.max_backoff,
+            supervisor: Supervisor::new("Websocket", config),
+            events: Events::new(),
+            connection: Mutex::new(None),
+            last_seen: StdMutex::new(Instant::now()),
+            pending: StdMutex::new(HashMap::new()),
+            next_id: AtomicU32::new(0),
+            generations: AtomicU64::new(0),
+        });
+        tokio::spawn(supervise(Arc::downgrade(&multiplexer)));
+        multiplexer
+    }
+
+    /// Whether this multiplexer can be kept for `config`
+    pub fn serves(&self, config: &ProxyConfig) -> bool {
+        config.forward_to == self.forward_to
+            && config.envelope.as_ref() == Some(&self.envelope)
+            && config.handshake == self.handshake
+            && config.heartbeat == self.heartbeat
+            && config.max_backoff == self.max_backoff
+    }
+
+    pub fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// Requests waiting for their response
+    pub fn waiting(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Connect to the backend, unless connecting failed recently and the backoff did not pass yet
+    async fn open(&self) -> Option<WsStream> {
+        if !self.supervisor.may_connect() {
+            return None;
+        }
+        self.connect().await
+    }
+
+    async fn connect(&self) -> Option<WsStream> {
+        match tokio::time::timeout(CONNECT_TIMEOUT, create_websocket_stream(self.forward_to.clone(), self.handshake.clone())).await.ok().flatten() {
+            Some(stream) => {
+                if let MaybeTlsStream::Plain(socket) = stream.get_ref() {
+                    supervisor::set_keepalive(socket, Duration::from_millis(self.heartbeat));
+                }
+                self.supervisor.connected();
+                Some(stream)
+            }
+            None => {
+                self.supervisor.failed();
+                None
+            }
+        }
+    }
+
+    fn attach(self: &Arc<Self>, stream: WsStream) -> Connection {
+        let generation = self.generations.fetch_add(1, Ordering::Relaxed);
+        *self.last_seen.lock().unwrap() = Instant::now();
+        let (sink, mut stream) = stream.split();
+        let this = Arc::downgrade(self);
+        let reader = tokio::spawn(async move {
+            while let Some(Ok(message)) = stream.next().await {
+                let Some(multiplexer) = this.upgrade() else { return };
+                *multiplexer.last_seen.lock().unwrap() = Instant::now();
+                multiplexer.dispatch(message);
+            }
+            if let Some(multiplexer) = this.upgrade() {
+                multiplexer.lost(generation).await;
+            }
+        });
+        Connection { sink, generation, reader }
+    }
+
+    fn dispatch(&self, message: Message) {
+        if !(message.is_text() || message.is_binary()) {
+            return;
+        }
+        let Some((id, response)) = self.envelope.open(message.clone()) else {
+            // not a response, the backend sent it on its own
+            match message {
+                Message::Text(text) => self.events.publish(Notification::Text(text)),
+                Message::Binary(bytes) => self.events.publish(Notification::Binary(bytes)),
+                _ => {}
+            }
+            return;
+        };
+        match self.pending.lock().unwrap().remove(&id) {
+            Some((_, waiting)) => {
+                let _ = waiting.send(response);
+            }
+            None => tracing::debug!("Ignoring a response to request {id} from Websocket server '{}', nobody is waiting for it", self.name),
+        }
+    }
+
+    /// Fail the requests sent on the connection of `generation`, it is replaced in the background
+    async fn lost(&self, generation: u64) {
+        self.pending.lock().unwrap().retain(|_, (sent_on, _)| *sent_on != generation);
+        let mut connection = self.connection.lock().await;
+        if connection.as_ref().is_some_and(|connection| connection.generation == generation) {
+            self.close(&mut connection);
+        }
+    }
+
+    /// Drop the connection and fail the requests waiting on it
+    fn close(&self, connection: &mut Option<Connection>) {
+        if let Some(previous) = connection.take() {
+            self.pending.lock().unwrap().retain(|_, (sent_on, _)| *sent_on != previous.generation);
+            self.supervisor.lost();
+        }
+    }
+
+    /// Replace the connection, requests waiting on the previous one fail
+    pub async fn reconnect(self: &Arc<Self>) -> bool {
+        // asked for explicitly, so the backoff is not waited for
+        let Some(stream) = self.connect().await else {
+            return false;
+        };
+        let mut connection = self.connection.lock().await;
+        if let Some(previous) = connection.take() {
+            self.pending.lock().unwrap().retain(|_, (sent_on, _)| *sent_on != previous.generation);
+        }
+        *connection = Some(self.attach(stream));
+        true
+    }
+
+    /// Send the request `id`, reconnecting once if the connection is gone. The connection is
+    /// only locked while sending, so responses are awaited concurrently
+    async fn send(self: &Arc<Self>, id: u32, message: Message) -> bool {
+        let mut connection = self.connection.lock().await;
+        for _ in 0..2 {
+            if connection.is_none() {
+                let Some(stream) = self.open().await else {
+                    return false;
+                };
+                *connection = Some(self.attach(stream));
+            }
+            let current = connection.as_mut().unwrap();
+            if let Some((sent_on, _)) = self.pending.lock().unwrap().get_mut(&id) {
+                *sent_on = current.generation;
+            }
+            match current.sink.send(message.clone()).await {
+                Ok(()) => return true,
+                Err(err) => {
+                    tracing::error!("Sending HTTP request to Websocket proxy error: {}", err);
+                    self.close(&mut connection);
+                }
+            }
+        }
+        false
+    }
+
+    /// Send `body` and wait up to `timeout` milliseconds for the response with its ID
+    pub async fn request(self: &Arc<Self>, body: Vec<u8>, timeout: u64) -> Result<Message, StatusCode> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = self.envelope.seal(id, body).map_err(|err| {
+            tracing::warn!("Rejecting request to Websocket server '{}': {err}", self.name);
+            StatusCode::BAD_REQUEST
+        })?;
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, (u64::MAX, sender));
+        if !self.send(id, message).await {
+            self.pending.lock().unwrap().remove(&id);
+            tracing::error!("No connection to Websocket server '{}', it is {}", self.name, self.supervisor.describe());
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+        match tokio::time::timeout(Duration::from_millis(timeout), receiver).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(StatusCode::BAD_GATEWAY),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                tracing::warn!("Websocket server timeout");
+                Err(StatusCode::GATEWAY_TIMEOUT)
+            }
+        }
+    }
+}
+
+/// Keep the multiplexer connected: ping the backend every `heartbeat`, and reconnect with
+/// backoff when the connection is lost or a ping is not answered
+async fn supervise(multiplexer: Weak<Multiplexer>) {
+    let mut last_beat = Instant::now();
+    let mut pinged: Option<Instant> = None;
+    loop {
+        let Some(delay) = multiplexer.upgrade().map(|multiplexer| multiplexer.supervisor.retry_in().min(CHECK_INTERVAL)) else { return };
+        tokio::time::sleep(delay).await;
+        let Some(multiplexer) = multiplexer.upgrade() else { return };
+        let mut connection = multiplexer.connection.lock().await;
+        let Some(current) = connection.as_mut() else {
+            if let Some(stream) = multiplexer.open().await {
+                *connection = Some(multiplexer.attach(stream));
+            }
+            (last_beat, pinged) = (Instant::now(), None);
+            continue;
+        };
+        if multiplexer.heartbeat == 0 {
+            continue;
+        }
+        if let Some(sent) = pinged {
+            if *multiplexer.last_seen.lock().unwrap() >= sent {
+                pinged = None;
+            } else if sent.elapsed() >= PONG_TIMEOUT {
+                tracing::debug!("Websocket server '{}' did not answer a ping", multiplexer.name);
+                multiplexer.close(&mut connection);
+                continue;
+            }
+        }
+        if pinged.is_none() && last_beat.elapsed() >= Duration::from_millis(multiplexer.heartbeat) {
+            last_beat = Instant::now();
+            match current.sink.send(Message::Ping(Vec::new())).await {
+                Ok(()) => pinged = Some(last_beat),
+                Err(_) => multiplexer.close(&mut connection),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::error::TryRecvError;
+    use super::*;
+
+    fn envelope(settings: &str) -> EnvelopeConfig {
+        toml::from_str(settings).unwrap()
+    }
+
+    fn json(text: &str) -> serde_json::Value {
+        serde_json::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn json_envelopes_round_trip() {
+        let envelope = envelope("format = \"json\"\nfield = \"request_id\"");
+        let Ok(Message::Text(sealed)) = envelope.seal(7, br#"{"query": "status"}"#.to_vec()) else { panic!("not sealed as text") };
+        assert_eq!(json(&sealed), json(r#"{"query": "status", "request_id": 7}"#));
+
+        let Some((7, Message::Text(opened))) = envelope.open(Message::Text(String::from(r#"{"request_id": 7, "ok": true}"#))) else {
+            panic!("not opened");
+        };
+        assert_eq!(json(&opened), json(r#"{"ok": true}"#));
+    }
+
+    #[test]
+    fn json_requests_must_be_objects() {
+        let envelope = envelope("format = \"json\"");
+        for body in ["[1, 2]", "\"text\"", "not json"] {
+            assert!(envelope.seal(1, body.as_bytes().to_vec()).is_err(), "{body}");
+        }
+    }
+
+    #[test]
+    fn binary_envelopes_round_trip() {
+        let envelope = envelope("format = \"binary\"");
+        assert_eq!(envelope.seal(0x01020304, b"xy".to_vec()), Ok(Message::Binary(b"\x01\x02\x03\x04xy".to_vec())));
+        assert_eq!(envelope.open(Message::Binary(b"\x00\x00\x01\x00z".to_vec())), Some((256, Message::Binary(b"z".to_vec()))));
+        assert_eq!(envelope.open(Message::Binary(b"\x00\x00\x00\x05".to_vec())), Some((5, Message::Binary(Vec::new()))));
+    }
+
+    #[test]
+    fn messages_without_an_id_are_not_responses() {
+        let json = envelope("format = \"json\"");
+        for text in [r#"{"event": "started"}"#, r#"{"id": "7"}"#, r#"{"id": -1}"#, r#"{"id": 4294967296}"#, "[7]", "not json"] {
+            assert_eq!(json.open(Message::Text(text.to_string())), None, "{text}");
+        }
+        assert_eq!(json.open(Message::Binary(br#"{"id": 7}"#.to_vec())), None);
+
+        let binary = envelope("format = \"binary\"");
+        assert_eq!(binary.open(Message::Binary(b"\x00\x00\x07".to_vec())), None);
+        assert_eq!(binary.open(Message::Text(String::from("\x00\x00\x00\x07"))), None);
+    }
+
+    fn multiplexer() -> Arc<Multiplexer> {
+        let config = toml::from_str("name = \"test\"\npath = \"/test\"\nforward_to = \"ws://127.0.0.1:9\"\ntimeout = 1000\nenvelope = { format = \"json\" }").unwrap();
+        Multiplexer::new(&config, envelope("format = \"json\""))
+    }
+
+    fn wait(multiplexer: &Multiplexer, id: u32) -> oneshot::Receiver<Message> {
+        let (sender, receiver) = oneshot::channel();
+        multiplexer.pending.lock().unwrap().insert(id, (0, sender));
+        receiver
+    }
+
+    #[tokio::test]
+    async fn dispatches_responses_to_the_request_with_their_id() {
+        let multiplexer = multiplexer();
+        let mut first = wait(&multiplexer, 1);
+        let mut second = wait(&multiplexer, 2);
+        multiplexer.dispatch(Message::Text(String::from(r#"{"id": 2, "result": "second"}"#)));
+        assert_eq!(second.try_recv(), Ok(Message::Text(String::from(r#"{"result":"second"}"#))));
+        assert!(first.try_recv().is_err());
+        assert_eq!(multiplexer.waiting(), 1);
+    }
+
+    #[tokio::test]
+    async fn drops_responses_nobody_waits_for() {
+        let multiplexer = multiplexer();
+        let mut events = multiplexer.events().subscribe();
+        let mut first = wait(&multiplexer, 1);
+        multiplexer.dispatch(Message::Text(String::from(r#"{"id": 3, "result": "late"}"#)));
+        assert!(first.try_recv().is_err());
+        assert_eq!(multiplexer.waiting(), 1);
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[tokio::test]
+    async fn publishes_messages_without_an_id_as_events() {
+        let multiplexer = multiplexer();
+        let mut events = multiplexer.events().subscribe();
+        let mut first = wait(&multiplexer, 1);
+        multiplexer.dispatch(Message::Text(String::from(r#"{"event": "started"}"#)));
+        multiplexer.dispatch(Message::Binary(b"\x00\x00\x00\x01".to_vec()));
+        assert!(first.try_recv().is_err());
+        assert_eq!(multiplexer.waiting(), 1);
+        assert!(matches!(events.try_recv(), Ok(Notification::Text(text)) if text == r#"{"event": "started"}"#));
+        assert!(matches!(events.try_recv(), Ok(Notification::Binary(bytes)) if bytes == b"\x00\x00\x00\x01"));
+        // control frames are neither
+        multiplexer.dispatch(Message::Ping(Vec::new()));
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+}
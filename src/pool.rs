@@ -0,0 +1,430 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use axum::http::StatusCode;
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use tokio::{
+    net::TcpStream,
+    select,
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    time::{Duration, Instant}
+};
+use tokio_tungstenite::{tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
+use crate::config::{ProxyConfig, HandshakeConfig};
+use crate::services::events::{self, Events, Notification};
+use crate::supervisor::{self, Supervisor, State, CHECK_INTERVAL, CONNECT_TIMEOUT, PONG_TIMEOUT};
+use crate::utils::{create_tcp_stream, create_websocket_stream};
+
+/// A connection to a backend that can be kept in a pool
+pub trait Backend: Sized + Send + 'static {
+    /// Names the kind of backend in logs
+    const SERVICE: &'static str;
+
+    /// Open a connection, `handshake` only applies to WebSocket backends
+    fn connect(forward_to: String, handshake: Option<HandshakeConfig>) -> impl Future<Output = Option<Self>> + Send;
+
+    /// The socket of the connection, to enable keepalive probes on it
+    fn socket(&self) -> Option<&TcpStream>;
+
+    /// Whether the idle connection can still be used, without waiting for anything. Data the
+    /// backend sent while it was idle is published to `events`, it is not a response
+    fn is_healthy(&mut self, events: &Events) -> bool;
+
+    /// Check that the backend still answers on the idle connection
+    fn heartbeat(&mut self, events: &Events) -> impl Future<Output = bool> + Send;
+}
+
+impl Backend for WebSocketStream<MaybeTlsStream<TcpStream>> {
+    const SERVICE: &'static str = "Websocket";
+
+    fn connect(forward_to: String, handshake: Option<HandshakeConfig>) -> impl Future<Output = Option<Self>> + Send {
+        create_websocket_stream(forward_to, handshake)
+    }
+
+    fn socket(&self) -> Option<&TcpStream> {
+        match self.get_ref() {
+            MaybeTlsStream::Plain(stream) => Some(stream),
+            _ => None,
+        }
+    }
+
+    fn is_healthy(&mut self, events: &Events) -> bool {
+        // messages already received are read without waiting
+        while let Some(next) = self.next().now_or_never() {
+            match next {
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Text(text))) => events.publish(Notification::Text(text)),
+                Some(Ok(Message::Binary(bytes))) => events.publish(Notification::Binary(bytes)),
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    async fn heartbeat(&mut self, events: &Events) -> bool {
+        if self.send(Message::Ping(Vec::new())).await.is_err() {
+            return false;
+        }
+        let pong = async {
+            while let Some(Ok(message)) = self.next().await {
+                match message {
+                    Message::Pong(_) => return true,
+                    Message::Text(text) => events.publish(Notification::Text(text)),
+                    Message::Binary(bytes) => events.publish(Notification::Binary(bytes)),
+                    _ => continue,
+                }
+            }
+            false
+        };
+        tokio::time::timeout(PONG_TIMEOUT, pong).await.unwrap_or(false)
+    }
+}
+
+impl Backend for TcpStream {
+    const SERVICE: &'static str = "TCP";
+
+    fn connect(forward_to: String, _handshake: Option<HandshakeConfig>) -> impl Future<Output = Option<Self>> + Send {
+        create_tcp_stream(forward_to)
+    }
+
+    fn socket(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+
+    fn is_healthy(&mut self, events: &Events) -> bool {
+        let mut buffer = [0; 4096];
+        loop {
+            match self.try_read(&mut buffer) {
+                // closed by the backend
+                Ok(0) => return false,
+                Ok(size) => events.publish(Notification::from_bytes(buffer[..size].to_vec())),
+                Err(err) => return err.kind() == std::io::ErrorKind::WouldBlock,
+            }
+        }
+    }
+
+    async fn heartbeat(&mut self, events: &Events) -> bool {
+        // the probes are sent by the system, a connection they found dead fails to read
+        self.is_healthy(events)
+    }
+}
+
+/// Connections to the backend of a proxy, a request checks one out and returns it when done
+pub struct Pool<C> {
+    name: String,
+    forward_to: String,
+    handshake: Option<HandshakeConfig>,
+    min_connections: usize,
+    max_connections: usize,
+    idle_timeout: u64,
+    heartbeat: u64,
+    max_backoff: u64,
+    supervisor: Supervisor,
+    events: Events,
+    idle: StdMutex<VecDeque<(C, Instant)>>,
+    permits: Arc<Semaphore>,
+    /// Requests waiting for a permit, a heartbeat gives its connection up for them
+    waiting: AtomicUsize,
+    wanted: Notify,
+    /// Connections of an older generation are closed instead of being returned
+    generation: AtomicU64,
+}
+
+impl<C: Backend> Pool<C> {
+    /// A pool for `config`, a background task connects to the backend and keeps it connected
+    pub fn new(config: &ProxyConfig) -> Arc<Self> {
+        let pool = Arc::new(Self {
+            name: config.name.clone(),
+            forward_to: config.forward_to.clone(),
+            handshake: config.handshake.clone(),
+            min_connections: config.min_connections,
+            max_connections: config.max_connections,
+            idle_timeout: config.idle_timeout,
+            heartbeat: config.heartbeat,
+            max_backoff: config.max_backoff,
+            supervisor: Supervisor::new(C::SERVICE, config),
+            events: Events::new(),
+            idle: StdMutex::new(VecDeque::new()),
+            permits: Arc::new(Semaphore::new(config.max_connections)),
+            waiting: AtomicUsize::new(0),
+            wanted: Notify::new(),
+            generation: AtomicU64::new(0),
+        });
+        tokio::spawn(supervise(Arc::downgrade(&pool)));
+        pool
+    }
+
+    /// Whether this pool can be kept for `config`
+    pub fn serves(&self, config: &ProxyConfig) -> bool {
+        config.forward_to == self.forward_to
+            && config.handshake == self.handshake
+            && config.min_connections == self.min_connections
+            && config.max_connections == self.max_connections
+            && config.idle_timeout == self.idle_timeout
+            && config.heartbeat == self.heartbeat
+            && config.max_backoff == self.max_backoff
+    }
+
+    pub fn supervisor(&self) -> &Supervisor {
+        &self.supervisor
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// Open a new connection, unless connecting failed recently and the backoff did not pass yet
+    async fn open(&self) -> Option<C> {
+        if !self.supervisor.may_connect() {
+            return None;
+        }
+        match tokio::time::timeout(CONNECT_TIMEOUT, C::connect(self.forward_to.clone(), self.handshake.clone())).await.ok().flatten() {
+            Some(connection) => {
+                if let Some(socket) = connection.socket() {
+                    supervisor::set_keepalive(socket, Duration::from_millis(self.heartbeat));
+                }
+                self.supervisor.connected();
+                Some(connection)
+            }
+            None => {
+                self.supervisor.failed();
+                None
+            }
+        }
+    }
+
+    /// Check out a connection, waiting while `max_connections` are in use. Idle connections
+    /// are reused when they are still healthy, otherwise a new one is opened if the backend is up
+    pub async fn get(self: &Arc<Self>) -> Option<Pooled<C>> {
+        let permit = {
+            let _waiting = Waiting::new(self);
+            self.permits.clone().acquire_owned().await.ok()?
+        };
+        let generation = self.generation.load(Ordering::Relaxed);
+        loop {
+            // the most recently used connection is the most likely to be alive
+            let Some((mut connection, _)) = self.idle.lock().unwrap().pop_back() else { break };
+            if connection.is_healthy(&self.events) {
+                return Some(Pooled { connection: Some(connection), pool: self.clone(), generation, _permit: permit });
+            }
+            tracing::debug!("Closing broken idle connection to '{}'", self.forward_to);
+        }
+        let connection = self.open().await?;
+        Some(Pooled { connection: Some(connection), pool: self.clone(), generation, _permit: permit })
+    }
+
+    /// Check out a connection like `get`, waiting at most `timeout` milliseconds for one to be free
+    pub async fn checkout(self: &Arc<Self>, timeout: u64) -> Result<Pooled<C>, StatusCode> {
+        match tokio::time::timeout(Duration::from_millis(timeout), self.get()).await {
+            Ok(Some(connection)) => Ok(connection),
+            Ok(None) => {
+                tracing::error!("No connection to {} server '{}', it is {}", C::SERVICE, self.name, self.supervisor.describe());
+                Err(StatusCode::BAD_GATEWAY)
+            }
+            Err(_) => {
+                tracing::warn!("All {} connections to '{}' are busy", self.max_connections, self.forward_to);
+                Err(StatusCode::SERVICE_UNAVAILABLE)
+            }
+        }
+    }
+
+    /// Keep `connection` for the next request, unless the pool was recycled since it was opened
+    fn put_back(&self, connection: C, generation: u64) -> bool {
+        let current = generation == self.generation.load(Ordering::Relaxed);
+        if current {
+            self.idle.lock().unwrap().push_back((connection, Instant::now()));
+        }
+        current
+    }
+
+    /// Close every connection and open `min_connections` new ones, connections in use are
+    /// closed when they are returned. False when the backend can not be reached
+    pub async fn recycle(self: &Arc<Self>) -> bool {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        self.idle.lock().unwrap().clear();
+        // asked for explicitly, so the backoff is not waited for
+        let Some(connection) = tokio::time::timeout(CONNECT_TIMEOUT, C::connect(self.forward_to.clone(), self.handshake.clone())).await.ok().flatten() else {
+            self.supervisor.failed();
+            return false;
+        };
+        if let Some(socket) = connection.socket() {
+            supervisor::set_keepalive(socket, Duration::from_millis(self.heartbeat));
+        }
+        self.supervisor.connected();
+        self.put_back(connection, generation);
+        self.fill().await;
+        true
+    }
+
+    /// Connections open now, idle and in use
+    pub fn size(&self) -> (usize, usize) {
+        (self.idle.lock().unwrap().len(), self.max_connections - self.permits.available_permits())
+    }
+
+    /// Open connections until there are `min_connections`
+    async fn fill(&self) {
+        let generation = self.generation.load(Ordering::Relaxed);
+        loop {
+            let (idle, in_use) = self.size();
+            if idle + in_use >= self.min_connections {
+                return;
+            }
+            let Some(connection) = self.open().await else {
+                return;
+            };
+            if !self.put_back(connection, generation) {
+                return;
+            }
+        }
+    }
+
+    /// Close idle connections that are broken, or unused for `idle_timeout` above `min_connections`.
+    /// The number of broken connections
+    fn expire(&self) -> usize {
+        let in_use = self.max_connections - self.permits.available_permits();
+        let mut idle = self.idle.lock().unwrap();
+        let before = idle.len();
+        idle.retain_mut(|(connection, _)| connection.is_healthy(&self.events));
+        let broken = before - idle.len();
+        if self.idle_timeout == 0 {
+            return broken;
+        }
+        let timeout = Duration::from_millis(self.idle_timeout);
+        // the oldest connections are in front
+        while idle.len() + in_use > self.min_connections
+            && idle.front().is_some_and(|(_, since)| since.elapsed() >= timeout) {
+            idle.pop_front();
+            tracing::debug!("Closing idle connection to '{}' of '{}'", self.forward_to, self.name);
+        }
+        broken
+    }
+
+    /// Send a heartbeat on each idle connection and close the ones that fail it. The number of
+    /// broken connections. Requests never wait for a heartbeat, it is skipped while one waits for
+    /// a connection, and the one in progress ends when a request comes in
+    async fn beat(&self) -> usize {
+        let mut broken = 0;
+        let count = self.idle.lock().unwrap().len();
+        let generation = self.generation.load(Ordering::Relaxed);
+        for _ in 0..count {
+            let wanted = self.wanted.notified();
+            tokio::pin!(wanted);
+            wanted.as_mut().enable();
+            if self.waiting.load(Ordering::Relaxed) > 0 {
+                break;
+            }
+            // connections being checked count as in use, so `max_connections` still holds
+            let Ok(_permit) = self.permits.try_acquire() else { break };
+            let Some((mut connection, since)) = self.idle.lock().unwrap().pop_front() else { break };
+            let healthy = select! {
+                healthy = connection.heartbeat(&self.events) => Some(healthy),
+                // a late pong is skipped when the connection is read
+                _ = &mut wanted => None,
+            };
+            if healthy == Some(false) {
+                broken += 1;
+                continue;
+            }
+            if generation == self.generation.load(Ordering::Relaxed) {
+                // a heartbeat does not count as use for `idle_timeout`
+                self.idle.lock().unwrap().push_back((connection, since));
+            }
+            if healthy.is_none() {
+                break;
+            }
+        }
+        broken
+    }
+}
+
+/// Keep the pool connected: close broken connections, send heartbeats, and reconnect with
+/// backoff once no connection is left
+async fn supervise<C: Backend>(pool: Weak<Pool<C>>) {
+    let mut last_beat = Instant::now();
+    loop {
+        let Some(delay) = pool.upgrade().map(|pool| {
+            // messages sent on idle connections are only read when they are checked
+            let interval = if pool.events.has_subscribers() { events::POLL_INTERVAL } else { CHECK_INTERVAL };
+            pool.supervisor.retry_in().min(interval)
+        }) else { return };
+        tokio::time::sleep(delay).await;
+        let Some(pool) = pool.upgrade() else { return };
+        let mut broken = pool.expire();
+        if pool.heartbeat > 0 && last_beat.elapsed() >= Duration::from_millis(pool.heartbeat) {
+            last_beat = Instant::now();
+            broken += pool.beat().await;
+        }
+        if broken > 0 {
+            tracing::debug!("Closed {broken} broken connections to {} server '{}'", C::SERVICE, pool.name);
+            if pool.size() == (0, 0) {
+                pool.supervisor.lost();
+            }
+        }
+        let (idle, in_use) = pool.size();
+        if pool.supervisor.state() != State::Connected && idle + in_use < pool.max_connections {
+            // requests may not come in to try again, and `fill` may already have enough connections
+            if let Some(connection) = pool.open().await {
+                pool.put_back(connection, pool.generation.load(Ordering::Relaxed));
+            }
+        }
+        pool.fill().await;
+    }
+}
+
+/// A connection checked out of a pool, it goes back to the pool when dropped
+pub struct Pooled<C: Backend> {
+    connection: Option<C>,
+    pool: Arc<Pool<C>>,
+    generation: u64,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<C: Backend> Pooled<C> {
+    /// Close the connection instead of returning it, after an error or a missed response
+    pub fn discard(mut self) {
+        self.connection = None;
+    }
+}
+
+impl<C: Backend> Deref for Pooled<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        self.connection.as_ref().unwrap()
+    }
+}
+
+impl<C: Backend> DerefMut for Pooled<C> {
+    fn deref_mut(&mut self) -> &mut C {
+        self.connection.as_mut().unwrap()
+    }
+}
+
+impl<C: Backend> Drop for Pooled<C> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.put_back(connection, self.generation);
+        }
+    }
+}
+
+/// A request waiting for a connection, counted in `waiting` until it has one or gives up
+struct Waiting<'a, C> {
+    pool: &'a Pool<C>,
+}
+
+impl<'a, C> Waiting<'a, C> {
+    fn new(pool: &'a Pool<C>) -> Self {
+        pool.waiting.fetch_add(1, Ordering::Relaxed);
+        pool.wanted.notify_waiters();
+        Self { pool }
+    }
+}
+
+impl<C> Drop for Waiting<'_, C> {
+    fn drop(&mut self) {
+        self.pool.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
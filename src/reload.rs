@@ -0,0 +1,222 @@
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+use axum::{
+    Router,
+    extract::Request,
+    http::StatusCode,
+    response::{IntoResponse, Response}
+};
+use lazy_static::lazy_static;
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::{Mutex, mpsc};
+use tower::{ServiceExt, service_fn};
+use crate::{
+    ServerContext,
+    create_router,
+    config::{self, SERVER_CONFIG, CONFIG_SOURCES, ServerConfig, Sources, ListenerConfig}
+};
+
+struct Running {
+    context: Arc<ServerContext>,
+    /// one router per listener, in the order of `BaseConfig::listeners`
+    routers: Vec<Router>,
+}
+
+lazy_static! {
+    static ref RUNNING: RwLock<Option<Running>> = RwLock::new(None);
+    static ref RELOAD_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// the listeners bound on startup, they stay the same until restart
+static LISTENERS: OnceLock<Vec<ListenerConfig>> = OnceLock::new();
+
+/// Build the routes of every listener for `context` and make them serve all new requests
+pub fn install(context: Arc<ServerContext>) {
+    let listeners = LISTENERS.get_or_init(|| SERVER_CONFIG.read().unwrap().server.listeners());
+    let routers = listeners.iter()
+        .map(|listener| create_router(context.clone(), listener).with_state(context.clone()))
+        .collect();
+    *RUNNING.write().unwrap() = Some(Running { context, routers });
+}
+
+pub fn current_context() -> Option<Arc<ServerContext>> {
+    RUNNING.read().unwrap().as_ref().map(|running| running.context.clone())
+}
+
+/// The service handed to the listener at `index`, it dispatches every request to the routes
+/// installed when the request arrives, so in-flight requests finish on the state they started with
+pub fn service(index: usize) -> Router {
+    Router::new().fallback_service(service_fn(move |req: Request| async move {
+        let router = RUNNING.read().unwrap().as_ref().and_then(|running| running.routers.get(index).cloned());
+        match router {
+            Some(router) => router.oneshot(req).await,
+            None => Ok::<Response, Infallible>(StatusCode::SERVICE_UNAVAILABLE.into_response()),
+        }
+    }))
+}
+
+/// Re-read the config file and apply everything that changed to the running server
+pub async fn reload() -> Result<String, String> {
+    let _lock = RELOAD_LOCK.lock().await;
+    let config_file = config::config_file();
+    let loaded = config::read_config(config_file).map_err(|err| err.to_string())?;
+    for warning in &loaded.warnings {
+        tracing::warn!("{warning}");
+    }
+    let (mut new_config, mut new_sources) = (loaded.config, loaded.sources);
+    let old_config = SERVER_CONFIG.read().unwrap().clone();
+
+    let changes = config::diff(&old_config, &new_config);
+    if changes.is_empty() {
+        return Ok(format!("No changes found in {config_file}"));
+    }
+    let mut report = vec![format!("Reloaded {config_file}:")];
+    for change in &changes {
+        if change.requires_restart() {
+            tracing::warn!("Config change `{change}` requires a restart to take effect");
+            report.push(format!("{change} (requires restart)"));
+        } else {
+            tracing::info!("Applying config change `{change}`");
+            report.push(change.to_string());
+        }
+    }
+    // the listener and logging keep running with the old settings until restart
+    new_config.server = old_config.server.clone();
+    new_sources.retain(|path, _| !path.starts_with("server."));
+    new_sources.extend(CONFIG_SOURCES.read().unwrap().iter()
+        .filter(|(path, _)| path.starts_with("server."))
+        .map(|(path, source)| (path.clone(), source.clone())));
+    apply(&old_config, new_config, new_sources).await;
+
+    Ok(report.join("\n"))
+}
+
+/// Connect the backends of `new_config` and make it the running config
+async fn apply(old_config: &ServerConfig, new_config: ServerConfig, new_sources: Sources) {
+    let previous = current_context();
+    let context = ServerContext::new(&new_config, previous.as_deref().map(|context| (old_config, context)));
+    *SERVER_CONFIG.write().unwrap() = new_config;
+    *CONFIG_SOURCES.write().unwrap() = new_sources;
+    install(Arc::new(context));
+}
+
+/// Set a single value of the running config, it is applied right away unless it needs a restart
+pub async fn set(path: &str, raw: &str) -> Result<String, String> {
+    let _lock = RELOAD_LOCK.lock().await;
+    let (path, loaded) = config::with_value(path, raw).map_err(|err| err.to_string())?;
+    for warning in &loaded.warnings {
+        tracing::warn!("{warning}");
+    }
+    let value = config::value_at(&loaded.config, &path).map_or_else(String::new, |value| value.to_string());
+    if config::requires_restart(&path) {
+        // saved with `config save`, the running server keeps the old value
+        *SERVER_CONFIG.write().unwrap() = loaded.config;
+        *CONFIG_SOURCES.write().unwrap() = loaded.sources;
+        tracing::warn!("Config value {path} was set to {value}, it requires a restart to take effect");
+        return Ok(format!("{path} = {value} (requires restart)"));
+    }
+    let old_config = SERVER_CONFIG.read().unwrap().clone();
+    apply(&old_config, loaded.config, loaded.sources).await;
+    tracing::info!("Config value {path} was set to {value}");
+    Ok(format!("{path} = {value}"))
+}
+
+async fn reload_and_log() {
+    match reload().await {
+        Ok(report) => tracing::info!("{report}"),
+        Err(err) => tracing::error!("Failed to reload configuration: {err}"),
+    }
+}
+
+/// Reload the config on SIGHUP, and on file changes if `server.watch_config` is set
+pub fn spawn_triggers() {
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                tracing::warn!("Failed to listen for SIGHUP, reload with `config reload` instead: {err}");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            tracing::info!("Received SIGHUP, reloading configuration...");
+            reload_and_log().await;
+        }
+    });
+
+    if SERVER_CONFIG.read().unwrap().server.watch_config {
+        spawn_file_watcher();
+    }
+}
+
+fn spawn_file_watcher() {
+    let config_file = config::config_file();
+    let config_path = Path::new(config_file);
+    let file_name = config_path.file_name().map(|name| name.to_os_string());
+    // included files are matched by their patterns, so new files in `conf.d` are picked up too
+    let directory = config_path.parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    // events carry absolute paths, so patterns are made absolute as well
+    let base = directory.canonicalize().unwrap_or_else(|_| directory.to_path_buf());
+    let patterns = SERVER_CONFIG.read().unwrap().include.iter()
+        .filter_map(|pattern| glob::Pattern::new(&base.join(pattern).to_string_lossy()).ok())
+        .collect::<Vec<_>>();
+    let watches_includes = !patterns.is_empty();
+    let mut directories = vec![directory.to_path_buf()];
+    for included in config::included_files() {
+        let parent = Path::new(&included).parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        if !directories.contains(&parent) {
+            directories.push(parent);
+        }
+    }
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            let relevant = |path: &std::path::PathBuf| {
+                path.file_name() == file_name.as_deref()
+                    || patterns.iter().any(|pattern| pattern.matches_path(path))
+            };
+            if (event.kind.is_modify() || event.kind.is_create() || event.kind.is_remove())
+                && event.paths.iter().any(relevant) {
+                let _ = tx.send(());
+            }
+        }
+    }) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            tracing::error!("Failed to create config file watcher: {err}");
+            return;
+        }
+    };
+    // watch the directories, editors often replace the file instead of writing it in place
+    for directory in &directories {
+        if let Err(err) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+            tracing::error!("Failed to watch {} for config changes: {err}", directory.display());
+            return;
+        }
+    }
+    if watches_includes {
+        tracing::info!("Watching {config_file} and the files it includes for changes");
+    } else {
+        tracing::info!("Watching {config_file} for changes");
+    }
+
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            // wait until the file settles, one save usually fires several events
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            while rx.try_recv().is_ok() {}
+            reload_and_log().await;
+        }
+    });
+}
@@ -0,0 +1,458 @@
+use std::future::Future;
+use axum::{
+    body::Body,
+    response::Response,
+    http::StatusCode
+};
+use futures_util::{StreamExt, stream};
+use serde::de::IgnoredAny;
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpStream,
+    time::Duration
+};
+use tokio_tungstenite::{tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
+use crate::config::{ProxyConfig, ResponseEnd};
+use crate::pool::{Backend, Pooled};
+use crate::services::{content_type, framing::{self, Codec}};
+use crate::utils::debug_print_bytes;
+
+/// A message of the backend, or bytes read from it
+#[derive(Default)]
+pub struct Part {
+    bytes: Vec<u8>,
+    /// Whether a WebSocket message was text
+    text: Option<bool>,
+}
+
+/// A backend connection the response to a request is read from
+pub trait Receive: Backend {
+    /// Whether every part is a whole message, otherwise parts are arbitrary pieces of a byte stream
+    const MESSAGES: bool;
+
+    /// The next part the backend sent, an error when the connection failed or was closed
+    fn receive(&mut self) -> impl Future<Output = Result<Part, StatusCode>> + Send;
+}
+
+impl Receive for WebSocketStream<MaybeTlsStream<TcpStream>> {
+    const MESSAGES: bool = true;
+
+    async fn receive(&mut self) -> Result<Part, StatusCode> {
+        loop {
+            match self.next().await {
+                Some(Ok(Message::Text(text))) => return Ok(Part { bytes: text.into_bytes(), text: Some(true) }),
+                Some(Ok(Message::Binary(bytes))) => return Ok(Part { bytes, text: Some(false) }),
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => {
+                    tracing::error!("Websocket server closed the connection before responding");
+                    return Err(StatusCode::BAD_GATEWAY);
+                }
+                Some(Err(err)) => {
+                    tracing::error!("WebSocket Connection Error: {}", err);
+                    return Err(StatusCode::BAD_GATEWAY);
+                }
+            }
+        }
+    }
+}
+
+impl Receive for TcpStream {
+    const MESSAGES: bool = false;
+
+    async fn receive(&mut self) -> Result<Part, StatusCode> {
+        let mut buffer = vec![0; 4096];
+        match self.read(&mut buffer).await {
+            Ok(0) => {
+                tracing::error!("TCP server closed the connection before responding");
+                Err(StatusCode::BAD_GATEWAY)
+            }
+            Ok(size) => {
+                buffer.truncate(size);
+                Ok(Part { bytes: buffer, text: None })
+            }
+            Err(err) => {
+                tracing::error!("TCP Connection Error: {}", err);
+                Err(StatusCode::BAD_GATEWAY)
+            }
+        }
+    }
+}
+
+enum End {
+    First,
+    Count(usize),
+    Terminator(Vec<u8>),
+    Idle(Duration),
+    Json,
+}
+
+/// Reads the parts of one response until the `response` setting of the proxy says it is complete
+struct Reader {
+    end: End,
+    /// Splits what a TCP backend sent into frames, each one is a part
+    codec: Option<Codec>,
+    /// Bytes read that do not make up a whole frame yet
+    frames: Vec<u8>,
+    /// Bytes at the start of `frames` already searched for a delimiter
+    searched: usize,
+    timeout: Duration,
+    received: usize,
+    /// Bytes kept to find the end of the response in
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl Reader {
+    fn new(config: &ProxyConfig) -> Self {
+        let end = match &config.response {
+            None => End::First,
+            Some(response) => match response.until {
+                ResponseEnd::Count => End::Count(response.count),
+                ResponseEnd::Terminator => End::Terminator(response.terminator.clone().into_bytes()),
+                ResponseEnd::Idle => End::Idle(Duration::from_millis(response.idle)),
+                ResponseEnd::Json => End::Json,
+            },
+        };
+        Self {
+            end,
+            codec: config.framing.as_ref().map(Codec::new),
+            frames: Vec::new(),
+            searched: 0,
+            timeout: Duration::from_millis(config.timeout),
+            received: 0,
+            buffer: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// The next part of the response, None once it is complete. `timeout` limits the wait for
+    /// every part, and after the first one `idle` ends the response instead
+    async fn next<C: Receive>(&mut self, connection: &mut C) -> Result<Option<Part>, StatusCode> {
+        while !self.done {
+            let idle = match self.end {
+                End::Idle(idle) if self.received > 0 => Some(idle),
+                _ => None,
+            };
+            let part = match tokio::time::timeout(idle.unwrap_or(self.timeout), self.receive(connection)).await {
+                Ok(part) => part?,
+                Err(_) if idle.is_some() => {
+                    self.done = true;
+                    break;
+                }
+                Err(_) => {
+                    tracing::warn!("{} server timeout", C::SERVICE);
+                    return Err(StatusCode::GATEWAY_TIMEOUT);
+                }
+            };
+            self.received += 1;
+            debug_print_bytes(&part.bytes, C::SERVICE);
+            match &self.end {
+                End::First => {
+                    self.done = true;
+                    return Ok(Some(part));
+                }
+                End::Count(count) => {
+                    self.done = self.received >= *count;
+                    return Ok(Some(part));
+                }
+                End::Idle(_) => return Ok(Some(part)),
+                End::Terminator(terminator) if C::MESSAGES || self.codec.is_some() => {
+                    if part.bytes == *terminator {
+                        self.done = true;
+                        break;
+                    }
+                    return Ok(Some(part));
+                }
+                End::Terminator(terminator) => {
+                    self.buffer.extend(part.bytes);
+                    if let Some(at) = framing::find(&self.buffer, terminator) {
+                        self.dropping(at + terminator.len(), C::SERVICE);
+                        self.buffer.truncate(at);
+                        self.done = true;
+                        return Ok(Some(Part { bytes: std::mem::take(&mut self.buffer), text: part.text }));
+                    }
+                    // the terminator may be split between reads, its start is kept until the next one
+                    let keep = self.buffer.len().saturating_sub(terminator.len().saturating_sub(1));
+                    if keep > 0 {
+                        let rest = self.buffer.split_off(keep);
+                        return Ok(Some(Part { bytes: std::mem::replace(&mut self.buffer, rest), text: part.text }));
+                    }
+                }
+                End::Json => {
+                    let start = self.buffer.len();
+                    self.buffer.extend(&part.bytes);
+                    let Some(end) = json_end(&self.buffer)? else {
+                        return Ok(Some(part));
+                    };
+                    self.dropping(end, C::SERVICE);
+                    self.done = true;
+                    return Ok(Some(Part { bytes: self.buffer[start..end].to_vec(), text: part.text }));
+                }
+            }
+        }
+        if !self.frames.is_empty() {
+            tracing::warn!("Dropping {} bytes the {} server sent after the last frame of a response", self.frames.len(), C::SERVICE);
+            self.frames.clear();
+            self.searched = 0;
+        }
+        Ok(None)
+    }
+
+    /// The next message or read of the backend, or the next frame when the proxy has framing
+    async fn receive<C: Receive>(&mut self, connection: &mut C) -> Result<Part, StatusCode> {
+        let Some(codec) = &self.codec else {
+            return connection.receive().await;
+        };
+        loop {
+            if let Some(frame) = codec.decode(&mut self.frames, &mut self.searched)? {
+                return Ok(Part { bytes: frame, text: None });
+            }
+            self.frames.extend(connection.receive().await?.bytes);
+        }
+    }
+
+    /// Warn about what the backend sent after the end of the response at `end` of the buffer
+    fn dropping(&self, end: usize, service: &str) {
+        if self.buffer.len() > end {
+            tracing::warn!("Dropping {} bytes the {service} server sent after the end of a response", self.buffer.len() - end);
+        }
+    }
+}
+
+/// Where the JSON document at the start of `buffer` ends, None while it is incomplete
+fn json_end(buffer: &[u8]) -> Result<Option<usize>, StatusCode> {
+    let mut documents = serde_json::Deserializer::from_slice(buffer).into_iter::<IgnoredAny>();
+    match documents.next() {
+        Some(Ok(_)) => Ok(Some(documents.byte_offset())),
+        Some(Err(err)) if err.is_eof() => Ok(None),
+        None => Ok(None),
+        Some(Err(err)) => {
+            tracing::error!("Response is not a valid JSON document: {err}");
+            Err(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+/// Read the response to the request just sent on `connection`, as one body or streamed to the
+/// client while it arrives. The connection is closed when the response fails or is not read
+/// completely, a late part would be taken for the response of the next request
+pub async fn response<C: Receive>(mut connection: Pooled<C>, config: &ProxyConfig) -> Result<Response, StatusCode> {
+    let mut reader = Reader::new(config);
+    let first = match reader.next(&mut *connection).await {
+        Ok(first) => first.unwrap_or_default(),
+        Err(err) => {
+            connection.discard();
+            return Err(err);
+        }
+    };
+    if !config.response.as_ref().is_some_and(|response| response.stream) {
+        let mut body = first.bytes;
+        loop {
+            match reader.next(&mut *connection).await {
+                Ok(Some(part)) => body.extend(part.bytes),
+                Ok(None) => break,
+                Err(err) => {
+                    connection.discard();
+                    return Err(err);
+                }
+            }
+        }
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", content_type::response(&config.content_type, &body, first.text))
+            .body(body.into())
+            .unwrap());
+    }
+
+    let content_type = content_type::response(&config.content_type, &first.bytes, first.text);
+    let streaming = Streaming { connection: Some(connection), reader };
+    let rest = stream::unfold(Some(streaming), |streaming| async move {
+        let mut streaming = streaming?;
+        let connection = streaming.connection.as_mut()?;
+        match streaming.reader.next(&mut **connection).await {
+            Ok(Some(part)) => Some((Ok(part.bytes), Some(streaming))),
+            Ok(None) => None,
+            // the client sees the response end early, the connection is closed with `streaming`
+            Err(err) => Some((Err(std::io::Error::other(err.to_string())), None)),
+        }
+    });
+    let body = stream::once(async { Ok(first.bytes) }).chain(rest);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .body(Body::from_stream(body))
+        .unwrap())
+}
+
+/// A connection the rest of a streamed response is read from
+struct Streaming<C: Receive> {
+    connection: Option<Pooled<C>>,
+    reader: Reader,
+}
+
+impl<C: Receive> Drop for Streaming<C> {
+    fn drop(&mut self) {
+        // the client went away or the response failed before it was complete
+        if !self.reader.done {
+            if let Some(connection) = self.connection.take() {
+                connection.discard();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use crate::config::HandshakeConfig;
+    use crate::services::events::Events;
+    use super::*;
+
+    /// A backend that sends the scripted parts, each after its delay in milliseconds, and then
+    /// nothing. `MESSAGES` picks between WebSocket messages and reads of a byte stream
+    struct Script<const MESSAGES: bool> {
+        parts: VecDeque<(u64, Result<String, StatusCode>)>,
+    }
+
+    impl<const MESSAGES: bool> Script<MESSAGES> {
+        fn new(parts: &[&str]) -> Self {
+            Self::delayed(&parts.iter().map(|part| (0, *part)).collect::<Vec<_>>())
+        }
+
+        fn delayed(parts: &[(u64, &str)]) -> Self {
+            Self { parts: parts.iter().map(|(delay, part)| (*delay, Ok(part.to_string()))).collect() }
+        }
+
+        fn rest(&self) -> Vec<String> {
+            self.parts.iter().filter_map(|(_, part)| part.clone().ok()).collect()
+        }
+    }
+
+    impl<const MESSAGES: bool> Backend for Script<MESSAGES> {
+        const SERVICE: &'static str = "Test";
+
+        async fn connect(_forward_to: String, _handshake: Option<HandshakeConfig>) -> Option<Self> {
+            None
+        }
+
+        fn socket(&self) -> Option<&TcpStream> {
+            None
+        }
+
+        fn is_healthy(&mut self, _events: &Events) -> bool {
+            true
+        }
+
+        async fn heartbeat(&mut self, _events: &Events) -> bool {
+            true
+        }
+    }
+
+    impl<const MESSAGES: bool> Receive for Script<MESSAGES> {
+        const MESSAGES: bool = MESSAGES;
+
+        async fn receive(&mut self) -> Result<Part, StatusCode> {
+            let Some((delay, _)) = self.parts.front() else {
+                return std::future::pending().await;
+            };
+            // a part is only taken once its delay passed, like data still on its way
+            tokio::time::sleep(Duration::from_millis(*delay)).await;
+            let (_, part) = self.parts.pop_front().unwrap();
+            part.map(|part| Part { bytes: part.into_bytes(), text: MESSAGES.then_some(true) })
+        }
+    }
+
+    fn config(settings: &str) -> ProxyConfig {
+        toml::from_str(&format!("name = \"test\"\npath = \"/test\"\nforward_to = \"test\"\ntimeout = 1000\n{settings}")).unwrap()
+    }
+
+    /// Every part of the response, as text
+    async fn read<const MESSAGES: bool>(settings: &str, backend: &mut Script<MESSAGES>) -> Result<Vec<String>, StatusCode> {
+        let mut reader = Reader::new(&config(settings));
+        let mut parts = Vec::new();
+        while let Some(part) = reader.next(backend).await? {
+            parts.push(String::from_utf8(part.bytes).unwrap());
+        }
+        Ok(parts)
+    }
+
+    #[tokio::test]
+    async fn takes_the_first_part_by_default() {
+        let mut backend = Script::<true>::new(&["one", "two"]);
+        assert_eq!(read("", &mut backend).await, Ok(vec![String::from("one")]));
+        assert_eq!(backend.rest(), ["two"]);
+    }
+
+    #[tokio::test]
+    async fn counts_messages() {
+        let mut backend = Script::<true>::new(&["one", "two", "next"]);
+        assert_eq!(read("response = { until = \"count\", count = 2 }", &mut backend).await, Ok(vec![String::from("one"), String::from("two")]));
+        assert_eq!(backend.rest(), ["next"]);
+    }
+
+    #[tokio::test]
+    async fn ends_at_a_terminator_message() {
+        let mut backend = Script::<true>::new(&["one", "END", "next"]);
+        assert_eq!(read("response = { until = \"terminator\", terminator = \"END\" }", &mut backend).await, Ok(vec![String::from("one")]));
+        assert_eq!(backend.rest(), ["next"]);
+    }
+
+    #[tokio::test]
+    async fn finds_a_terminator_split_between_reads() {
+        let settings = "response = { until = \"terminator\", terminator = \"<END>\" }";
+        for split in 1..5 {
+            let (head, tail) = "<END>".split_at(split);
+            let mut backend = Script::<false>::new(&[&format!("hello{head}"), &format!("{tail}dropped"), "next"]);
+            let parts = read(settings, &mut backend).await.unwrap();
+            assert_eq!(parts.concat(), "hello", "split at {split}");
+            assert_eq!(backend.rest(), ["next"]);
+        }
+        // a part of the terminator that is not followed by the rest belongs to the response
+        let mut backend = Script::<false>::new(&["a<EN", "b<END>"]);
+        assert_eq!(read(settings, &mut backend).await.unwrap().concat(), "a<ENb");
+    }
+
+    #[tokio::test]
+    async fn counts_frames_of_a_byte_stream() {
+        let mut backend = Script::<false>::new(&["one\ntw", "o\nthree\n", "next\n"]);
+        let settings = "framing = { codec = \"lines\" }\nresponse = { until = \"count\", count = 2 }";
+        assert_eq!(read(settings, &mut backend).await, Ok(vec![String::from("one"), String::from("two")]));
+        assert_eq!(backend.rest(), ["next\n"]);
+    }
+
+    #[tokio::test]
+    async fn ends_when_the_backend_is_idle() {
+        let mut backend = Script::<false>::delayed(&[(0, "one"), (20, "two"), (500, "late")]);
+        assert_eq!(read("response = { until = \"idle\", idle = 200 }", &mut backend).await, Ok(vec![String::from("one"), String::from("two")]));
+        assert_eq!(backend.rest(), ["late"]);
+    }
+
+    #[tokio::test]
+    async fn collects_a_json_document_over_several_reads() {
+        let mut backend = Script::<false>::new(&["{\"list\": [1, ", "2], \"text\": \"}\"", "} {\"next\": true}", "next"]);
+        let parts = read("response = { until = \"json\" }", &mut backend).await.unwrap();
+        assert_eq!(parts.concat(), "{\"list\": [1, 2], \"text\": \"}\"}");
+        assert_eq!(backend.rest(), ["next"]);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_reply_that_is_not_json() {
+        let mut backend = Script::<false>::new(&["not json"]);
+        assert_eq!(read("response = { until = \"json\" }", &mut backend).await, Err(StatusCode::BAD_GATEWAY));
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_connection_closes_midway() {
+        let mut backend = Script::<true>::new(&["one"]);
+        backend.parts.push_back((0, Err(StatusCode::BAD_GATEWAY)));
+        assert_eq!(read("response = { until = \"count\", count = 2 }", &mut backend).await, Err(StatusCode::BAD_GATEWAY));
+    }
+
+    #[tokio::test]
+    async fn times_out_without_a_reply() {
+        let mut backend = Script::<true>::new(&[]);
+        let settings = "response = { until = \"count\", count = 1 }";
+        let mut reader = Reader { timeout: Duration::from_millis(20), ..Reader::new(&config(settings)) };
+        assert_eq!(reader.next(&mut backend).await.err(), Some(StatusCode::GATEWAY_TIMEOUT));
+    }
+}
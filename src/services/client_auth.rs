@@ -0,0 +1,53 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response}
+};
+use crate::config::SERVER_CONFIG;
+use crate::tls::ClientCert;
+
+/// Reject requests to the paths that require a client certificate when the connection has none,
+/// the subject of a verified certificate is passed to reverse proxy backends in a header
+pub async fn check(mut req: Request, next: Next) -> Response {
+    let client_auth = SERVER_CONFIG.read().unwrap().server.tls.as_ref().and_then(|tls| tls.client_auth.clone());
+    let Some(client_auth) = client_auth else {
+        return next.run(req).await;
+    };
+    let header = HeaderName::try_from(client_auth.header.as_str()).ok();
+    // a subject sent by the client itself must never reach a backend
+    if let Some(header) = &header {
+        req.headers_mut().remove(header);
+    }
+    match req.extensions().get::<ClientCert>().cloned() {
+        Some(client_cert) => {
+            if let (Some(header), Ok(value)) = (header, HeaderValue::from_bytes(client_cert.subject.as_bytes())) {
+                req.headers_mut().insert(header, value);
+            }
+        }
+        None if client_auth.requires(req.uri().path()) => {
+            tracing::debug!("Rejecting request to {} without a client certificate", req.uri().path());
+            return (StatusCode::FORBIDDEN, "A client certificate is required").into_response();
+        }
+        None => {}
+    }
+    next.run(req).await
+}
+
+/// Add the subject of the client certificate to a JSON object `body` sent to a websocket or TCP
+/// backend, other bodies are forwarded as they are
+pub fn envelope(body: Vec<u8>, client_cert: Option<&ClientCert>) -> Vec<u8> {
+    let field = SERVER_CONFIG.read().unwrap().server.tls.as_ref()
+        .and_then(|tls| tls.client_auth.as_ref())
+        .and_then(|client_auth| client_auth.field.clone());
+    let (Some(field), Some(client_cert)) = (field, client_cert) else {
+        return body;
+    };
+    match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(serde_json::Value::Object(mut object)) => {
+            object.insert(field, serde_json::Value::String(client_cert.subject.clone()));
+            serde_json::to_vec(&object).unwrap_or(body)
+        }
+        _ => body,
+    }
+}
@@ -0,0 +1,64 @@
+use axum::http::HeaderValue;
+
+/// `content_type` setting that guesses the type from the response body
+pub const SNIFF: &str = "sniff";
+
+/// `content_type` setting that derives the type from the WebSocket message type
+pub const MESSAGE: &str = "message";
+
+const TEXT: &str = "text/plain; charset=utf-8";
+const BINARY: &str = "application/octet-stream";
+
+/// Types recognized by the first bytes of the body
+const SIGNATURES: [(&[u8], &str); 7] = [
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+];
+
+/// The Content-Type of a response for the `content_type` setting of a proxy, `text` tells
+/// whether the backend answered with a WebSocket text message, if it uses messages
+pub fn response(setting: &str, body: &[u8], text: Option<bool>) -> HeaderValue {
+    let content_type = match (setting, text) {
+        (SNIFF, _) | (MESSAGE, None) => sniff(body),
+        (MESSAGE, Some(true)) => TEXT,
+        (MESSAGE, Some(false)) => BINARY,
+        (fixed, _) => fixed,
+    };
+    HeaderValue::from_str(content_type).unwrap_or(HeaderValue::from_static(BINARY))
+}
+
+/// Guess the type of `body` from well-known signatures, JSON, markup and plain text
+pub fn sniff(body: &[u8]) -> &'static str {
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(signature, _)| body.starts_with(signature)) {
+        return content_type;
+    }
+    let Ok(text) = std::str::from_utf8(body) else {
+        return BINARY;
+    };
+    let start = text.trim_start().get(..14).unwrap_or(text.trim_start()).to_ascii_lowercase();
+    if start.starts_with(['{', '[']) && serde_json::from_str::<serde::de::IgnoredAny>(text).is_ok() {
+        "application/json"
+    } else if start.starts_with("<?xml") {
+        "application/xml"
+    } else if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        "text/html; charset=utf-8"
+    } else if text.chars().any(|c| c.is_control() && !c.is_ascii_whitespace()) {
+        BINARY
+    } else {
+        TEXT
+    }
+}
+
+/// Whether a body of `content_type` is text, like JSON, XML or form data
+pub fn is_textual(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || ["application/json", "application/xml", "application/javascript", "application/x-www-form-urlencoded"].contains(&essence.as_str())
+}
@@ -0,0 +1,166 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use axum::{
+    Router,
+    routing::get,
+    response::{IntoResponse, Response, sse::{Event, KeepAlive, Sse}},
+    http::StatusCode,
+    extract::{Query, State}
+};
+use data_encoding::BASE64;
+use futures_util::{Stream, stream};
+use serde::Deserialize;
+use tokio::{
+    sync::broadcast,
+    time::Duration
+};
+use crate::{
+    ServerContext,
+    config::{SERVER_CONFIG, ProxyKind, ProxyConfig}
+};
+
+/// How often idle pooled connections are checked for messages while clients are subscribed
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Messages kept for subscribers that fall behind, older ones are skipped
+const CAPACITY: usize = 256;
+
+/// A message the backend sent on its own, outside of a response
+#[derive(Clone)]
+pub enum Notification {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Notification {
+    /// Bytes read from a TCP backend, text when they are valid UTF-8
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => Notification::Text(text),
+            Err(err) => Notification::Binary(err.into_bytes()),
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Notification::Text(text) => text.as_bytes(),
+            Notification::Binary(bytes) => bytes,
+        }
+    }
+}
+
+/// The notifications of one backend, handed to every subscribed client
+pub struct Events {
+    sender: broadcast::Sender<Notification>,
+}
+
+impl Events {
+    pub fn new() -> Self {
+        Self { sender: broadcast::channel(CAPACITY).0 }
+    }
+
+    pub fn publish(&self, notification: Notification) {
+        if self.has_subscribers() {
+            let _ = self.sender.send(notification);
+        }
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.sender.subscribe()
+    }
+}
+
+/// Which notifications a client wants, given in the query of the events path
+#[derive(Deserialize)]
+pub struct Filter {
+    /// Dotted path of a field the JSON object of the message must have
+    field: Option<String>,
+    /// The value `field` must have, numbers and booleans are compared as written in JSON
+    value: Option<String>,
+    /// Text or bytes the message must start with
+    prefix: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, notification: &Notification) -> bool {
+        if self.prefix.as_ref().is_some_and(|prefix| !notification.bytes().starts_with(prefix.as_bytes())) {
+            return false;
+        }
+        let Some(field) = &self.field else {
+            return true;
+        };
+        let Ok(message) = serde_json::from_slice::<serde_json::Value>(notification.bytes()) else {
+            return false;
+        };
+        let found = field.split('.').try_fold(&message, |value, key| value.get(key));
+        match (found, &self.value) {
+            (Some(serde_json::Value::String(found)), Some(value)) => found == value,
+            (Some(found), Some(value)) => serde_json::from_str::<serde_json::Value>(value).is_ok_and(|value| *found == value),
+            (None, Some(_)) => false,
+            (found, None) => found.is_some(),
+        }
+    }
+}
+
+/// Mount the events path of the proxy `config`, if it has one
+pub fn setup_route(router: Router<Arc<ServerContext>>, config: &ProxyConfig, kind: ProxyKind) -> Router<Arc<ServerContext>> {
+    let Some(path) = config.events_path() else {
+        return router;
+    };
+    let name = config.name.clone();
+    tracing::info!("Setting up route for events of {} service '{}'", kind.key(), config.name);
+    router.route(&path, get(move |state: State<Arc<ServerContext>>, filter: Query<Filter>| subscribe(state, filter, kind, name)))
+}
+
+async fn subscribe(
+    State(context): State<Arc<ServerContext>>,
+    Query(filter): Query<Filter>,
+    kind: ProxyKind,
+    name: String,
+) -> Response {
+    let config = SERVER_CONFIG.read().unwrap().proxy(kind, &name).and_then(|proxy| proxy.events.clone());
+    let events = match kind {
+        ProxyKind::WebSocket => context.ws_multiplexed.get(&name).map(|multiplexer| multiplexer.events())
+            .or_else(|| context.ws_proxy.get(&name).map(|pool| pool.events())),
+        ProxyKind::Tcp => context.tcp_proxy.get(&name).map(|pool| pool.events()),
+        ProxyKind::Reverse => None,
+    };
+    let (Some(config), Some(events)) = (config, events) else {
+        tracing::error!("Access events of {} service '{name}' without setting up", kind.key());
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    tracing::debug!("Client subscribed to events of {} service '{name}'", kind.key());
+    let sse = Sse::new(stream(events.subscribe(), filter));
+    if config.keepalive == 0 {
+        return sse.into_response();
+    }
+    sse.keep_alive(KeepAlive::new().interval(Duration::from_millis(config.keepalive))).into_response()
+}
+
+/// The notifications matching `filter` as server-sent events, binary messages are sent as
+/// base64 in events of the type `binary`. It ends when the backend settings of the proxy change
+fn stream(receiver: broadcast::Receiver<Notification>, filter: Filter) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(notification) if filter.matches(&notification) => {
+                    let event = match notification {
+                        // lines are sent as separate data fields, which do not allow carriage returns
+                        Notification::Text(text) => Event::default().data(text.replace("\r\n", "\n").replace('\r', "\n")),
+                        Notification::Binary(bytes) => Event::default().event("binary").data(BASE64.encode(&bytes)),
+                    };
+                    return Some((Ok(event), (receiver, filter)));
+                }
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Skipped {skipped} events for a client that could not keep up");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
@@ -0,0 +1,245 @@
+use axum::http::StatusCode;
+use crate::config::{FramingCodec, FramingConfig};
+
+/// Frames larger than this are refused, a broken length prefix would otherwise allocate gigabytes
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// Splits the byte stream of a TCP backend into frames, and turns requests into frames
+pub enum Codec {
+    Delimited { delimiter: Vec<u8>, lines: bool },
+    Length { size: usize, big_endian: bool },
+    Fixed(usize),
+}
+
+impl Codec {
+    pub fn new(config: &FramingConfig) -> Self {
+        match config.codec {
+            FramingCodec::Lines => Codec::Delimited { delimiter: b"\n".to_vec(), lines: true },
+            FramingCodec::Delimiter => Codec::Delimited { delimiter: config.delimiter.clone().into_bytes(), lines: false },
+            FramingCodec::U16be => Codec::Length { size: 2, big_endian: true },
+            FramingCodec::U16le => Codec::Length { size: 2, big_endian: false },
+            FramingCodec::U32be => Codec::Length { size: 4, big_endian: true },
+            FramingCodec::U32le => Codec::Length { size: 4, big_endian: false },
+            FramingCodec::Fixed => Codec::Fixed(config.size),
+        }
+    }
+
+    /// The request `body` as one frame. A delimiter is only added when the body does not end
+    /// with it already, and must not appear anywhere else
+    pub fn encode(&self, mut body: Vec<u8>) -> Result<Vec<u8>, StatusCode> {
+        match self {
+            Codec::Delimited { delimiter, .. } => {
+                let content = body.strip_suffix(delimiter.as_slice()).unwrap_or(&body);
+                if find(content, delimiter).is_some() {
+                    tracing::warn!("Rejecting a request that contains the delimiter of its frame");
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                if content.len() == body.len() {
+                    body.extend(delimiter);
+                }
+                Ok(body)
+            }
+            Codec::Length { size, big_endian } => {
+                let limit = if *size == 2 { u16::MAX as usize } else { (u32::MAX as usize).min(MAX_FRAME) };
+                if body.len() > limit {
+                    tracing::warn!("Rejecting a request of {} bytes, frames have at most {limit} bytes", body.len());
+                    return Err(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+                let length = (body.len() as u32).to_be_bytes();
+                let mut prefix = length[4 - size..].to_vec();
+                if !big_endian {
+                    prefix.reverse();
+                }
+                prefix.extend(body);
+                Ok(prefix)
+            }
+            Codec::Fixed(size) => {
+                if body.len() != *size {
+                    tracing::warn!("Rejecting a request of {} bytes, records have {size} bytes", body.len());
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                Ok(body)
+            }
+        }
+    }
+
+    /// Take the first frame out of `buffer`, None while it is incomplete. The first `searched`
+    /// bytes are known to hold no delimiter, the search goes on after them
+    pub fn decode(&self, buffer: &mut Vec<u8>, searched: &mut usize) -> Result<Option<Vec<u8>>, StatusCode> {
+        let frame = match self {
+            Codec::Delimited { delimiter, lines } => match find(&buffer[*searched..], delimiter) {
+                Some(at) => {
+                    let at = *searched + at;
+                    *searched = 0;
+                    let mut frame = buffer.drain(..at + delimiter.len()).collect::<Vec<_>>();
+                    frame.truncate(at);
+                    if *lines && frame.last() == Some(&b'\r') {
+                        frame.pop();
+                    }
+                    Some(frame)
+                }
+                None if buffer.len() > MAX_FRAME => return Err(too_large(buffer.len())),
+                None => {
+                    // a delimiter split between reads starts in the last bytes
+                    *searched = (buffer.len() + 1).saturating_sub(delimiter.len());
+                    None
+                }
+            },
+            Codec::Length { size, big_endian } => {
+                if buffer.len() < *size {
+                    return Ok(None);
+                }
+                let mut length = [0; 4];
+                length[4 - size..].copy_from_slice(&buffer[..*size]);
+                if !big_endian {
+                    length[4 - size..].reverse();
+                }
+                let length = u32::from_be_bytes(length) as usize;
+                if length > MAX_FRAME {
+                    return Err(too_large(length));
+                }
+                if buffer.len() < size + length {
+                    return Ok(None);
+                }
+                Some(buffer.drain(..size + length).skip(*size).collect())
+            }
+            Codec::Fixed(size) if buffer.len() >= *size => Some(buffer.drain(..*size).collect()),
+            Codec::Fixed(_) => None,
+        };
+        Ok(frame)
+    }
+}
+
+fn too_large(size: usize) -> StatusCode {
+    tracing::error!("Frame of the TCP server is larger than {MAX_FRAME} bytes: {size}");
+    StatusCode::BAD_GATEWAY
+}
+
+pub fn find(bytes: &[u8], pattern: &[u8]) -> Option<usize> {
+    bytes.windows(pattern.len()).position(|window| window == pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delimited(delimiter: &str) -> Codec {
+        Codec::Delimited { delimiter: delimiter.as_bytes().to_vec(), lines: false }
+    }
+
+    /// Feed `reads` one after the other and collect the frames decoded after each
+    fn decode_reads(codec: &Codec, reads: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut buffer = Vec::new();
+        let mut searched = 0;
+        let mut frames = Vec::new();
+        for read in reads {
+            buffer.extend_from_slice(read);
+            while let Some(frame) = codec.decode(&mut buffer, &mut searched).unwrap() {
+                frames.push(frame);
+            }
+            assert!(searched <= buffer.len());
+        }
+        frames
+    }
+
+    #[test]
+    fn finds_a_delimiter_split_between_reads() {
+        let codec = delimited("<END>");
+        for split in 0..=5 {
+            let (head, tail) = b"<END>".split_at(split);
+            let frames = decode_reads(&codec, &[b"first", head, tail, b"second<E", b"ND>"]);
+            assert_eq!(frames, [b"first".to_vec(), b"second".to_vec()], "split at {split}");
+        }
+    }
+
+    #[test]
+    fn resumes_the_search_where_it_stopped() {
+        let codec = delimited("<END>");
+        let mut buffer = b"abcdef<EN".to_vec();
+        let mut searched = 0;
+        assert_eq!(codec.decode(&mut buffer, &mut searched), Ok(None));
+        // the start of the delimiter is searched again
+        assert_eq!(searched, 5);
+        buffer.extend_from_slice(b"D>rest");
+        assert_eq!(codec.decode(&mut buffer, &mut searched), Ok(Some(b"abcdef".to_vec())));
+        assert_eq!((buffer.as_slice(), searched), (&b"rest"[..], 0));
+    }
+
+    #[test]
+    fn strips_carriage_returns_of_lines() {
+        let codec = Codec::Delimited { delimiter: b"\n".to_vec(), lines: true };
+        let frames = decode_reads(&codec, &[b"one\r", b"\ntwo\n\r\n"]);
+        assert_eq!(frames, [b"one".to_vec(), b"two".to_vec(), Vec::new()]);
+        // only lines drop it
+        assert_eq!(decode_reads(&delimited("\n"), &[b"one\r\n"]), [b"one\r".to_vec()]);
+    }
+
+    #[test]
+    fn refuses_delimited_frames_over_the_limit() {
+        let codec = delimited("\n");
+        let mut buffer = vec![b'x'; MAX_FRAME + 1];
+        assert_eq!(codec.decode(&mut buffer, &mut 0), Err(StatusCode::BAD_GATEWAY));
+    }
+
+    #[test]
+    fn decodes_length_prefixes() {
+        let big = Codec::Length { size: 2, big_endian: true };
+        assert_eq!(decode_reads(&big, &[b"\x00", b"\x03ab", b"c\x00\x00"]), [b"abc".to_vec(), Vec::new()]);
+        let little = Codec::Length { size: 4, big_endian: false };
+        assert_eq!(decode_reads(&little, &[b"\x02\x00\x00", b"\x00hi"]), [b"hi".to_vec()]);
+    }
+
+    #[test]
+    fn waits_for_a_truncated_length_prefix() {
+        let codec = Codec::Length { size: 4, big_endian: true };
+        let mut buffer = b"\x00\x00\x00".to_vec();
+        assert_eq!(codec.decode(&mut buffer, &mut 0), Ok(None));
+        buffer.extend_from_slice(b"\x01");
+        assert_eq!(codec.decode(&mut buffer, &mut 0), Ok(None));
+        buffer.extend_from_slice(b"x");
+        assert_eq!(codec.decode(&mut buffer, &mut 0), Ok(Some(b"x".to_vec())));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn refuses_an_oversized_length() {
+        let codec = Codec::Length { size: 4, big_endian: true };
+        let mut buffer = ((MAX_FRAME + 1) as u32).to_be_bytes().to_vec();
+        assert_eq!(codec.decode(&mut buffer, &mut 0), Err(StatusCode::BAD_GATEWAY));
+        let mut buffer = (MAX_FRAME as u32).to_be_bytes().to_vec();
+        assert_eq!(codec.decode(&mut buffer, &mut 0), Ok(None));
+    }
+
+    #[test]
+    fn decodes_fixed_records() {
+        let codec = Codec::Fixed(3);
+        assert_eq!(decode_reads(&codec, &[b"ab", b"cdefg", b"h"]), [b"abc".to_vec(), b"def".to_vec()]);
+    }
+
+    #[test]
+    fn encodes_delimited_requests() {
+        let codec = delimited("\r\n");
+        assert_eq!(codec.encode(b"ping".to_vec()), Ok(b"ping\r\n".to_vec()));
+        // an existing delimiter at the end is kept as the only one
+        assert_eq!(codec.encode(b"ping\r\n".to_vec()), Ok(b"ping\r\n".to_vec()));
+        assert_eq!(codec.encode(b"pi\r\nng".to_vec()), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(codec.encode(b"ping\r\n\r\n".to_vec()), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn encodes_length_prefixes() {
+        let big = Codec::Length { size: 2, big_endian: true };
+        assert_eq!(big.encode(b"abc".to_vec()), Ok(b"\x00\x03abc".to_vec()));
+        let little = Codec::Length { size: 4, big_endian: false };
+        assert_eq!(little.encode(b"abc".to_vec()), Ok(b"\x03\x00\x00\x00abc".to_vec()));
+        assert_eq!(big.encode(vec![0; u16::MAX as usize + 1]), Err(StatusCode::PAYLOAD_TOO_LARGE));
+        assert_eq!(little.encode(vec![0; MAX_FRAME + 1]), Err(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    #[test]
+    fn encodes_fixed_records() {
+        let codec = Codec::Fixed(3);
+        assert_eq!(codec.encode(b"abc".to_vec()), Ok(b"abc".to_vec()));
+        assert_eq!(codec.encode(b"ab".to_vec()), Err(StatusCode::BAD_REQUEST));
+    }
+}
@@ -4,3 +4,64 @@ pub mod reverse_proxy;
 pub mod web;
 pub mod api;
 pub mod default;
+pub mod redirect;
+pub mod client_auth;
+pub mod aggregate;
+pub mod framing;
+pub mod content_type;
+pub mod events;
+
+use crate::config::{ServerConfig, ListenerConfig};
+
+pub struct RouteEntry {
+    pub method: &'static str,
+    pub path: String,
+    pub service: String,
+}
+
+/// The path that matches everything below `path`
+pub fn wildcard_path(path: &str) -> String {
+    if path.ends_with('/') {
+        format!("{path}*path")
+    } else {
+        format!("{path}/*path")
+    }
+}
+
+/// All routes `create_router` mounts on `listener` when every backend is reachable
+pub fn route_table(config: &ServerConfig, listener: &ListenerConfig) -> Vec<RouteEntry> {
+    let mut routes = Vec::new();
+    let mut add = |method, path: &str, service: String| {
+        routes.push(RouteEntry { method, path: path.to_string(), service });
+    };
+    for proxy in config.websocket_proxy.iter().filter(|proxy| listener.serves("websocket_proxy", Some(&proxy.name))) {
+        add("POST", &proxy.path, format!("websocket_proxy '{}' -> {}", proxy.name, proxy.forward_to));
+        if proxy.passthrough {
+            add("GET", &proxy.path, format!("websocket_proxy '{}' -> {} (passthrough)", proxy.name, proxy.forward_to));
+        }
+        if let Some(path) = proxy.events_path() {
+            add("GET", &path, format!("websocket_proxy '{}' <- {} (events)", proxy.name, proxy.forward_to));
+        }
+    }
+    for proxy in config.tcp_proxy.iter().filter(|proxy| listener.serves("tcp_proxy", Some(&proxy.name))) {
+        add("POST", &proxy.path, format!("tcp_proxy '{}' -> {}", proxy.name, proxy.forward_to));
+        if let Some(path) = proxy.events_path() {
+            add("GET", &path, format!("tcp_proxy '{}' <- {} (events)", proxy.name, proxy.forward_to));
+        }
+    }
+    for proxy in config.reverse_proxy.iter().filter(|proxy| listener.serves("reverse_proxy", Some(&proxy.name))) {
+        let service = format!("reverse_proxy '{}' -> {}", proxy.name, proxy.forward_to);
+        add("GET", &proxy.path, service.clone());
+        add("GET", &wildcard_path(&proxy.path), service);
+    }
+    if listener.serves("api", None) {
+        add("POST", "/api", String::from("api"));
+    }
+    if let Some(web) = config.web.as_ref().filter(|_| listener.serves("web", None)) {
+        let service = format!("web -> {}", web.dist_path);
+        add("GET", &web.path, service.clone());
+        add("GET", &wildcard_path(&web.path), service);
+    }
+    add("*", "*", String::from("not found page"));
+    routes
+}
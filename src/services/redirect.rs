@@ -0,0 +1,33 @@
+use axum::{
+    Router,
+    http::{StatusCode, Uri, HeaderMap, header},
+    response::{IntoResponse, Response}
+};
+
+/// The service of the plain HTTP listener, every request is redirected to HTTPS on `https_port`
+pub fn router(https_port: Option<u16>) -> Router {
+    Router::new().fallback(move |uri: Uri, headers: HeaderMap| redirect(uri, headers, https_port))
+}
+
+async fn redirect(uri: Uri, headers: HeaderMap, https_port: Option<u16>) -> Response {
+    let Some(host) = headers.get(header::HOST).and_then(|host| host.to_str().ok()) else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    // drop the port of the plain listener, keep the brackets of IPv6 addresses
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    let authority = match https_port {
+        Some(443) | None => host.to_string(),
+        Some(port) => format!("{host}:{port}"),
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let location = format!("https://{authority}{path}");
+    tracing::debug!("Redirecting {uri} to {location}");
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(header::LOCATION, location)
+        .body("".into())
+        .unwrap()
+}
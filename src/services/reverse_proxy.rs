@@ -3,33 +3,34 @@ use axum::{
     Router,
     routing::get,
     extract::{Request, State},
-    http::uri::{PathAndQuery, Uri},
+    http::{Version, uri::{PathAndQuery, Uri}},
     response::{IntoResponse, Response},
 };
 use http_body_util::BodyExt;
 use hyper::StatusCode;
 use crate::{
     ServerContext,
-    config::SERVER_CONFIG
+    config::{SERVER_CONFIG, ProxyKind, ListenerConfig}
 };
+use super::wildcard_path;
 
-pub fn setup_routes(router: Router<Arc<ServerContext>>) -> Router<Arc<ServerContext>> {
-    if let Some(config) = &SERVER_CONFIG.read().unwrap().reverse_proxy {
+pub fn setup_routes(mut router: Router<Arc<ServerContext>>, listener: &ListenerConfig) -> Router<Arc<ServerContext>> {
+    for config in &SERVER_CONFIG.read().unwrap().reverse_proxy {
+        if !listener.serves("reverse_proxy", Some(&config.name)) {
+            continue;
+        }
         let path = config.path.as_str();
-        let get_file_path = if path.ends_with("/") {
-            format!("{path}*path")
-        } else {
-            format!("{path}/*path")
-        };
+        let get_file_path = wildcard_path(path);
         let get_file_path = get_file_path.as_str();
+        let name = config.name.clone();
+        let handler = move |state: State<Arc<ServerContext>>, req: Request| forward_to(state, req, name);
 
-        tracing::info!("Setting up route for Reverse proxy service");
-        router
-            .route(path, get(forward_to))
-            .route(get_file_path, get(forward_to))
-    } else {
-        router
+        tracing::info!("Setting up route for Reverse proxy service '{}'", config.name);
+        router = router
+            .route(path, get(handler.clone()))
+            .route(get_file_path, get(handler));
     }
+    router
 }
 
 fn insert_base_tag(contents: &mut String, base_href: &str) {
@@ -51,10 +52,11 @@ fn insert_base_tag(contents: &mut String, base_href: &str) {
 async fn forward_to(
     State(context): State<Arc<ServerContext>>,
     mut req: Request,
+    name: String,
 ) -> Result<Response, StatusCode> {
     let config = {
         let guard = SERVER_CONFIG.read().unwrap();
-        guard.reverse_proxy.clone()
+        guard.proxy(ProxyKind::Reverse, &name).cloned()
     };
     if let Some(config) = config {
         // modify req uri
@@ -69,6 +71,8 @@ async fn forward_to(
         let uri = format!("{}{}", config.forward_to, path_query);
 
         *req.uri_mut() = Uri::try_from(uri).unwrap();
+        // clients may talk HTTP/2 to the gateway, the backend is reached over HTTP/1.1
+        *req.version_mut() = Version::HTTP_11;
 
         // get response
         let mut response = context
@@ -114,7 +118,7 @@ async fn forward_to(
 
         Ok(response.into_response())
     } else {
-        tracing::error!("Access reverse proxy endpoint without setting up");
+        tracing::error!("Access reverse proxy endpoint '{name}' without setting up");
         Err(StatusCode::INTERNAL_SERVER_ERROR)
     }
 }
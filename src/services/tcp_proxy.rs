@@ -7,100 +7,75 @@ use axum::{
     extract::{State, Request}
 };
 use tokio::{
-    select,
-    time::Duration,
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::MutexGuard
+    io::AsyncWriteExt,
+    net::TcpStream
 };
 use crate::{
     ServerContext,
-    config::SERVER_CONFIG
+    config::{SERVER_CONFIG, ProxyKind, ListenerConfig},
+    pool::Pooled,
+    services::{aggregate, client_auth, events, framing::Codec},
+    tls::ClientCert
 };
-use crate::utils::{get_body_from_request, debug_print_bytes, create_tcp_stream};
+use crate::utils::{get_body_from_request, debug_print_bytes};
 
-pub fn setup_routes(router: Router<Arc<ServerContext>>) -> Router<Arc<ServerContext>> {
-    if let Some(config) = &SERVER_CONFIG.read().unwrap().tcp_proxy {
+pub fn setup_routes(mut router: Router<Arc<ServerContext>>, context: &ServerContext, listener: &ListenerConfig) -> Router<Arc<ServerContext>> {
+    for config in &SERVER_CONFIG.read().unwrap().tcp_proxy {
+        if !context.tcp_proxy.contains_key(&config.name) || !listener.serves("tcp_proxy", Some(&config.name)) {
+            continue;
+        }
         let path = config.path.as_str();
+        let name = config.name.clone();
 
-        tracing::info!("Setting up route for TCP proxy service");
-        router
-            .route(path, post(forward_to))
-    } else {
-        router
+        tracing::info!("Setting up route for TCP proxy service '{}'", config.name);
+        router = router
+            .route(path, post(move |state: State<Arc<ServerContext>>, req: Request| forward_to(state, req, name)));
+        router = events::setup_route(router, config, ProxyKind::Tcp);
     }
+    router
 }
 
 async fn forward_to(
     State(context): State<Arc<ServerContext>>,
     req: Request,
+    name: String,
 ) -> Result<Response, StatusCode> {
     let config = {
         let guard = SERVER_CONFIG.read().unwrap();
-        guard.tcp_proxy.clone()
+        guard.proxy(ProxyKind::Tcp, &name).cloned()
     };
-    if let (Some(config), Some(tcp)) = (config, &context.tcp_proxy) {
-        let body_bytes = get_body_from_request(req).await?;
+    if let (Some(config), Some(pool)) = (config, context.tcp_proxy.get(&name)) {
+        let client_cert = req.extensions().get::<ClientCert>().cloned();
+        let body_bytes = client_auth::envelope(get_body_from_request(req).await?, client_cert.as_ref());
         debug_print_bytes(&body_bytes, "HTTP");
-        let mut tcp = tcp.lock().await;
-        match handler(&mut tcp, body_bytes.clone(), config.timeout).await {
-            Ok(response) => Ok(response),
-            Err(err) if err == StatusCode::BAD_GATEWAY => {
+        let body_bytes = match &config.framing {
+            Some(framing) => Codec::new(framing).encode(body_bytes)?,
+            None => body_bytes,
+        };
+        let tcp = pool.checkout(config.timeout).await?;
+        let tcp = match send(tcp, &body_bytes).await {
+            Ok(tcp) => tcp,
+            Err(_) => {
                 tracing::warn!("Failure when connecting to TCP server, try to reconnect");
-                match create_tcp_stream(config.forward_to.clone()).await {
-                    Some(new_tcp) => {
-                        *tcp = new_tcp;
-                        tracing::info!("Reconnected to TCP server");
-                        handler(&mut tcp, body_bytes, config.timeout).await
-                    }
-                    None => {
-                        tracing::error!("Failed to reconnect to TCP server");
-                        Err(StatusCode::BAD_GATEWAY)
-                    }
-                }
-            },
-            Err(err) => Err(err),
-        }
+                let tcp = pool.checkout(config.timeout).await?;
+                tracing::info!("Reconnected to TCP server");
+                send(tcp, &body_bytes).await?
+            }
+        };
+        // the backend may have acted on the request, so a failed reply is not sent again
+        aggregate::response(tcp, &config).await
     } else {
-        tracing::error!("Access TCP proxy endpoint without setting up");
+        tracing::error!("Access TCP proxy endpoint '{name}' without setting up");
         Err(StatusCode::INTERNAL_SERVER_ERROR)
     }
 }
 
-async fn handler(tcp: &mut MutexGuard<'_, TcpStream>, body_bytes: Vec<u8>, timeout: u64) -> Result<Response, StatusCode> {
-    // send request to server
-    if let Err(err) = tcp.write_all(body_bytes.as_slice()).await {
+/// Send the request to the server, the connection is closed when that fails
+async fn send(mut tcp: Pooled<TcpStream>, body_bytes: &[u8]) -> Result<Pooled<TcpStream>, StatusCode> {
+    if let Err(err) = tcp.write_all(body_bytes).await {
         tracing::error!("Sending HTTP request to TCP server error: {}", err);
+        tcp.discard();
         return Err(StatusCode::BAD_GATEWAY);
     }
-    // wait for response (timeout: 1s)
-    let mut buffer = vec![0; 4096];
-    select! {
-        result = tcp.read(&mut buffer) => {
-            match result {
-                Ok(n) if n > 0 => {
-                    let msg = &buffer[..n];
-                    let msg = msg.to_vec();
-                    debug_print_bytes(&msg, "TCP");
-                    Ok(Response::builder()
-                        .status(StatusCode::OK)
-                        .header("Content-Type", "application/json")
-                        .body(msg.into())
-                        .unwrap())
-                }
-                Ok(n) => {
-                    tracing::error!("TCP Connection Error: received message include {} byte(s)", n);
-                    Err(StatusCode::BAD_GATEWAY)
-                }
-                Err(err) => {
-                    tracing::error!("TCP Connection Error: {}", err);
-                    Err(StatusCode::BAD_GATEWAY)
-                }
-            }
-        }
-        _ = tokio::time::sleep(Duration::from_millis(timeout)) => {
-            tracing::warn!("TCP server timeout");
-            Err(StatusCode::GATEWAY_TIMEOUT)
-        }
-    }
+    Ok(tcp)
 }
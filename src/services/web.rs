@@ -16,18 +16,14 @@ use crate::{
     ServerContext,
     config::SERVER_CONFIG
 };
+use super::wildcard_path;
 
 const NOT_FOUND: &str = include_str!("./not_found.html");
 
 pub fn setup_routes(router: Router<Arc<ServerContext>>) -> Router<Arc<ServerContext>> {
     if let Some(config) = &SERVER_CONFIG.read().unwrap().web {
         let path = config.path.as_str();
-        let get_file_path = if path.ends_with("/") {
-            format!("{path}")
-        } else {
-            format!("{path}/")
-        };
-        let get_file_path = format!("{get_file_path}*path");
+        let get_file_path = wildcard_path(path);
         let get_file_path = get_file_path.as_str();
 
         tracing::info!("Setting up route for Web service");
@@ -1,118 +1,282 @@
 use std::sync::Arc;
 use axum::{
     Router,
-    routing::post,
-    response::Response,
-    http::StatusCode,
+    body::Body,
+    routing::MethodRouter,
+    response::{IntoResponse, Response},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
     extract::{State, Request}
 };
+use hyper_util::rt::TokioIo;
 use tokio::{
     select,
     time::Duration,
-    net::TcpStream,
-    sync::MutexGuard
+    net::TcpStream
 };
-use tokio_tungstenite::{tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
-use futures_util::{StreamExt, SinkExt};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{
+        Error as WsError,
+        handshake::derive_accept_key,
+        protocol::{Message, Role}
+    },
+    MaybeTlsStream, WebSocketStream
+};
+use futures_util::{Sink, Stream, StreamExt, SinkExt};
 use crate::{
     ServerContext,
-    config::SERVER_CONFIG
+    config::{SERVER_CONFIG, ProxyKind, ProxyConfig, ListenerConfig, FrameType},
+    pool::Pooled,
+    services::{aggregate, client_auth, content_type, events},
+    tls::ClientCert
 };
-use crate::utils::{get_body_from_request, debug_print_bytes, create_websocket_stream};
-
-pub fn setup_routes(router: Router<Arc<ServerContext>>) -> Router<Arc<ServerContext>> {
-    if let Some(config) = &SERVER_CONFIG.read().unwrap().websocket_proxy {
-        let path = config.path.as_str();
+use crate::utils::{get_body_from_request, debug_print_bytes, websocket_request};
 
-        tracing::info!("Setting up route for Websocket proxy service");
-        router
-            .route(path, post(forward_to))
-    } else {
-        router
+pub fn setup_routes(mut router: Router<Arc<ServerContext>>, context: &ServerContext, listener: &ListenerConfig) -> Router<Arc<ServerContext>> {
+    for config in &SERVER_CONFIG.read().unwrap().websocket_proxy {
+        if !listener.serves("websocket_proxy", Some(&config.name)) {
+            continue;
+        }
+        let mut route = MethodRouter::new();
+        let mut mounted = false;
+        if context.ws_proxy.contains_key(&config.name) || context.ws_multiplexed.contains_key(&config.name) {
+            let name = config.name.clone();
+            tracing::info!("Setting up route for Websocket proxy service '{}'", config.name);
+            route = route.post(move |state: State<Arc<ServerContext>>, req: Request| forward_to(state, req, name));
+            mounted = true;
+        }
+        // passthrough connections have their own backend connection, they do not need the shared one
+        if config.passthrough {
+            let name = config.name.clone();
+            tracing::info!("Setting up WebSocket passthrough for Websocket proxy service '{}'", config.name);
+            route = route.get(move |req: Request| passthrough(req, name));
+            mounted = true;
+        }
+        if mounted {
+            router = router.route(config.path.as_str(), route);
+        }
+        router = events::setup_route(router, config, ProxyKind::WebSocket);
     }
+    router
 }
 
 async fn forward_to(
     State(context): State<Arc<ServerContext>>,
     req: Request,
+    name: String,
 ) -> Result<Response, StatusCode> {
     let config = {
         let guard = SERVER_CONFIG.read().unwrap();
-        guard.websocket_proxy.clone()
+        guard.proxy(ProxyKind::WebSocket, &name).cloned()
     };
-    if let (Some(config), Some(ws)) = (config, &context.ws_proxy) {
-        let body_bytes = get_body_from_request(req).await?;
+    if let (Some(config), Some(multiplexer)) = (&config, context.ws_multiplexed.get(&name)) {
+        let client_cert = req.extensions().get::<ClientCert>().cloned();
+        let body_bytes = client_auth::envelope(get_body_from_request(req).await?, client_cert.as_ref());
+        debug_print_bytes(&body_bytes, "HTTP");
+        let response = multiplexer.request(body_bytes, config.timeout).await?;
+        return message_response(response, &config.content_type);
+    }
+    if let (Some(config), Some(pool)) = (config, context.ws_proxy.get(&name)) {
+        let client_cert = req.extensions().get::<ClientCert>().cloned();
+        let request_type = req.headers().get(header::CONTENT_TYPE).cloned();
+        let body_bytes = client_auth::envelope(get_body_from_request(req).await?, client_cert.as_ref());
         debug_print_bytes(&body_bytes, "HTTP");
-        let mut ws = ws.lock().await;
-        match handler(&mut ws, body_bytes.clone(), config.timeout).await {
-            Ok(response) => Ok(response),
-            Err(err) if err == StatusCode::BAD_GATEWAY => {
+        let message = request_message(config.frame, request_type.as_ref(), body_bytes)?;
+        let ws = pool.checkout(config.timeout).await?;
+        let ws = match send(ws, message.clone()).await {
+            Ok(ws) => ws,
+            Err(_) => {
                 tracing::warn!("Failure when connecting to Websocket server, try to reconnect");
-                match create_websocket_stream(config.forward_to.clone()).await {
-                    Some(new_ws) => {
-                        *ws = new_ws;
-                        tracing::info!("Reconnected to Websocket server");
-                        handler(&mut ws, body_bytes, config.timeout).await
-                    },
-                    None => {
-                        tracing::error!("Failed to reconnect to Websocket server");
-                        Err(StatusCode::BAD_GATEWAY)
-                    }
-                }
-            },
-            Err(err) => Err(err),
-        }
+                let ws = pool.checkout(config.timeout).await?;
+                tracing::info!("Reconnected to Websocket server");
+                send(ws, message).await?
+            }
+        };
+        // the backend may have acted on the request, so a failed reply is not sent again
+        aggregate::response(ws, &config).await
     } else {
-        tracing::error!("Access Websocket proxy endpoint without setting up");
+        tracing::error!("Access Websocket proxy endpoint '{name}' without setting up");
         Err(StatusCode::INTERNAL_SERVER_ERROR)
     }
 }
 
-async fn handler(ws: &mut MutexGuard<'_, WebSocketStream<MaybeTlsStream<TcpStream>>>, body_bytes: Vec<u8>, timeout: u64) -> Result<Response, StatusCode> {
-    // send request to server
-    let request_message = Message::Binary(body_bytes);
+/// The message sending `body` to the backend, with the type chosen by `frame`
+fn request_message(frame: FrameType, request_type: Option<&HeaderValue>, body: Vec<u8>) -> Result<Message, StatusCode> {
+    let text = match frame {
+        FrameType::Binary => false,
+        FrameType::Text => true,
+        FrameType::Auto => request_type
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(content_type::is_textual),
+    };
+    if !text {
+        return Ok(Message::Binary(body));
+    }
+    match String::from_utf8(body) {
+        Ok(text) => Ok(Message::Text(text)),
+        Err(err) if frame == FrameType::Auto => {
+            tracing::debug!("Sending a request that is not valid UTF-8 as a binary message");
+            Ok(Message::Binary(err.into_bytes()))
+        }
+        Err(_) => {
+            tracing::warn!("Rejecting a request that is not valid UTF-8, it can not be sent as a text message");
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+type Connection = Pooled<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// Send the request to the server, the connection is closed when that fails
+async fn send(mut ws: Connection, request_message: Message) -> Result<Connection, StatusCode> {
     if let Err(err) = ws.send(request_message).await {
         tracing::error!("Sending HTTP request to Websocket proxy error: {}", err);
+        ws.discard();
         return Err(StatusCode::BAD_GATEWAY);
     }
-    // wait for response (timeout: 1s)
-    select! {
-        Some(result) = ws.next() => {
-            match result {
-                Ok(msg) => {
-                    match msg {
-                        Message::Text(response_text) => {
-                            let response_text_bin = response_text.clone().into_bytes();
-                            debug_print_bytes(&response_text_bin, "Websocket");
-                            Ok(Response::builder()
-                                .status(StatusCode::OK)
-                                .header("Content-Type", "application/json")
-                                .body(response_text.into())
-                                .unwrap())
-                        }
-                        Message::Binary(response_binary) => {
-                            debug_print_bytes(&response_binary, "Websocket");
-                            Ok(Response::builder()
-                                .status(StatusCode::OK)
-                                .header("Content-Type", "application/json")
-                                .body(response_binary.into())
-                                .unwrap())
-                        }
-                        _ => {
-                            tracing::warn!("Received message is an unsupported WebSocket message type");
-                            Err(StatusCode::BAD_GATEWAY)
-                        }
-                    }
-                }
-                Err(err) => {
-                    tracing::error!("WebSocket Connection Error: {}", err);
-                    Err(StatusCode::BAD_GATEWAY)
-                }
+    Ok(ws)
+}
+
+/// The HTTP response with a message of the backend, its Content-Type follows the
+/// `content_type` setting of the proxy
+fn message_response(message: Message, content_type: &str) -> Result<Response, StatusCode> {
+    match message {
+        Message::Text(response_text) => {
+            let response_text_bin = response_text.clone().into_bytes();
+            debug_print_bytes(&response_text_bin, "Websocket");
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", content_type::response(content_type, &response_text_bin, Some(true)))
+                .body(response_text.into())
+                .unwrap())
+        }
+        Message::Binary(response_binary) => {
+            debug_print_bytes(&response_binary, "Websocket");
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", content_type::response(content_type, &response_binary, Some(false)))
+                .body(response_binary.into())
+                .unwrap())
+        }
+        _ => {
+            tracing::warn!("Received message is an unsupported WebSocket message type");
+            Err(StatusCode::BAD_GATEWAY)
+        }
+    }
+}
+
+/// The `Sec-WebSocket-Key` of a valid WebSocket upgrade request
+fn websocket_key(headers: &HeaderMap) -> Option<&HeaderValue> {
+    let has_token = |name, token: &str| headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token));
+    if !has_token(header::CONNECTION, "upgrade") || !has_token(header::UPGRADE, "websocket")
+        || headers.get(header::SEC_WEBSOCKET_VERSION).is_none_or(|version| version != "13") {
+        return None;
+    }
+    headers.get(header::SEC_WEBSOCKET_KEY)
+}
+
+/// Accept a WebSocket from the client once a dedicated connection to the backend is open,
+/// and relay the messages between both until either side closes
+async fn passthrough(mut req: Request, name: String) -> Response {
+    let config = SERVER_CONFIG.read().unwrap().proxy(ProxyKind::WebSocket, &name).cloned();
+    let Some(config) = config else {
+        tracing::error!("Access Websocket proxy endpoint '{name}' without setting up");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let Some(key) = websocket_key(req.headers()) else {
+        return (StatusCode::UPGRADE_REQUIRED, [(header::UPGRADE, "websocket")], "Expected a WebSocket upgrade").into_response();
+    };
+    let accept = derive_accept_key(key.as_bytes());
+    let (backend, protocol) = match connect_backend(&config, req.headers()).await {
+        Ok(connected) => connected,
+        Err(err) => {
+            tracing::error!("Failed to open WebSocket passthrough to {}: {err}", config.forward_to);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let client = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+                tracing::debug!("WebSocket passthrough to Websocket proxy service '{name}' opened");
+                relay(client, backend).await;
+                tracing::debug!("WebSocket passthrough to Websocket proxy service '{name}' closed");
             }
+            Err(err) => tracing::debug!("WebSocket upgrade for Websocket proxy service '{name}' failed: {err}"),
         }
-        _ = tokio::time::sleep(Duration::from_millis(timeout)) => {
-            tracing::warn!("Websocket server timeout");
-            Err(StatusCode::GATEWAY_TIMEOUT)
+    });
+
+    let mut response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept);
+    // the client only accepts a subprotocol it offered, the backend picks one of them
+    if let Some(protocol) = protocol {
+        response = response.header(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    response.body(Body::empty()).unwrap()
+}
+
+/// Open a connection to the backend of `config` with its handshake settings and the headers
+/// of the client it forwards. The subprotocols the client offered replace the configured ones,
+/// the one the backend picked out of them is returned
+async fn connect_backend(config: &ProxyConfig, headers: &HeaderMap)
+    -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Option<HeaderValue>), WsError> {
+    let mut request = websocket_request(&config.forward_to, config.handshake.as_ref(), Some(headers)).map_err(|err| *err)?;
+    let offered = headers.get_all(header::SEC_WEBSOCKET_PROTOCOL).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .collect::<Vec<_>>();
+    if !offered.is_empty() {
+        let protocols = HeaderValue::try_from(offered.join(",")).map_err(axum::http::Error::from)?;
+        request.headers_mut().insert(header::SEC_WEBSOCKET_PROTOCOL, protocols);
+    }
+    let connect = tokio::time::timeout(Duration::from_millis(config.timeout), connect_async(request));
+    let (stream, response) = connect.await
+        .map_err(|_| WsError::Io(std::io::ErrorKind::TimedOut.into()))??;
+    // a subprotocol only the proxy offered is not passed on, the client did not ask for it
+    let protocol = response.headers().get(header::SEC_WEBSOCKET_PROTOCOL).filter(|_| !offered.is_empty());
+    Ok((stream, protocol.cloned()))
+}
+
+async fn relay<C, B>(client: C, backend: B)
+where
+    C: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Unpin,
+    B: Stream<Item = Result<Message, WsError>> + Sink<Message, Error = WsError> + Unpin,
+{
+    let (client_tx, client_rx) = client.split();
+    let (backend_tx, backend_rx) = backend.split();
+    let upstream = forward(client_rx, backend_tx);
+    let downstream = forward(backend_rx, client_tx);
+    tokio::pin!(upstream, downstream);
+    // once one side is done, give the other one a moment to finish the close handshake
+    let rest = select! {
+        _ = &mut upstream => tokio::time::timeout(Duration::from_secs(5), downstream).await,
+        _ = &mut downstream => tokio::time::timeout(Duration::from_secs(5), upstream).await,
+    };
+    if rest.is_err() {
+        tracing::debug!("WebSocket passthrough closed without a close handshake");
+    }
+}
+
+/// Send every message of `from` to `to`, close frames included, then close `to`
+async fn forward<R, W>(mut from: R, mut to: W)
+where
+    R: Stream<Item = Result<Message, WsError>> + Unpin,
+    W: Sink<Message, Error = WsError> + Unpin,
+{
+    while let Some(Ok(message)) = from.next().await {
+        if to.send(message).await.is_err() {
+            break;
         }
     }
+    let _ = to.close().await;
 }
@@ -0,0 +1,160 @@
+use std::sync::Mutex as StdMutex;
+use rand::Rng;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    net::TcpStream,
+    time::{Duration, Instant}
+};
+use crate::config::ProxyConfig;
+
+/// Delay before the first attempt to reconnect, doubled after every failed attempt
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// How often connections are checked, idle ones expired and lost ones replaced
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long opening a connection may take before the attempt counts as failed
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a heartbeat waits for the backend to answer a ping
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    /// Not connected yet, or the connection was lost and is being replaced
+    Connecting,
+    Connected,
+    /// Connecting failed, the next attempt waits for the backoff
+    Disconnected,
+}
+
+impl State {
+    fn describe(&self) -> &'static str {
+        match self {
+            State::Connecting => "connecting",
+            State::Connected => "connected",
+            State::Disconnected => "disconnected",
+        }
+    }
+}
+
+struct Status {
+    state: State,
+    since: Instant,
+    failures: u32,
+    retry_at: Instant,
+}
+
+/// The connection state of a backend and when to try reconnecting to it
+pub struct Supervisor {
+    service: &'static str,
+    name: String,
+    max_backoff: Duration,
+    status: StdMutex<Status>,
+}
+
+impl Supervisor {
+    /// `service` names the kind of backend in logs, like `Websocket` or `TCP`
+    pub fn new(service: &'static str, config: &ProxyConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            service,
+            name: config.name.clone(),
+            max_backoff: Duration::from_millis(config.max_backoff),
+            status: StdMutex::new(Status { state: State::Connecting, since: now, failures: 0, retry_at: now }),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.status.lock().unwrap().state
+    }
+
+    /// Whether a new connection may be opened now, false while waiting for the backoff
+    pub fn may_connect(&self) -> bool {
+        let status = self.status.lock().unwrap();
+        status.state != State::Disconnected || status.retry_at <= Instant::now()
+    }
+
+    /// Time left until the next attempt to reconnect, zero when it is due
+    pub fn retry_in(&self) -> Duration {
+        let status = self.status.lock().unwrap();
+        match status.state {
+            State::Connected => Duration::MAX,
+            _ => status.retry_at.saturating_duration_since(Instant::now()),
+        }
+    }
+
+    pub fn connected(&self) {
+        let mut status = self.status.lock().unwrap();
+        if status.state != State::Connected {
+            match status.failures {
+                0 => tracing::info!("Connected to {} server '{}'", self.service, self.name),
+                failures => tracing::info!("Reconnected to {} server '{}' after {failures} failed attempts", self.service, self.name),
+            }
+            status.state = State::Connected;
+            status.since = Instant::now();
+        }
+        status.failures = 0;
+    }
+
+    pub fn failed(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.failures += 1;
+        let delay = self.backoff(status.failures);
+        let now = Instant::now();
+        status.retry_at = now + delay;
+        if status.state != State::Disconnected {
+            status.state = State::Disconnected;
+            status.since = now;
+        }
+        tracing::warn!("Failed to connect to {} server '{}', retrying in {}", self.service, self.name, format_duration(delay));
+    }
+
+    /// The backend closed the connection or stopped answering, reconnect right away
+    pub fn lost(&self) {
+        let mut status = self.status.lock().unwrap();
+        if status.state == State::Connected {
+            tracing::warn!("Connection to {} server '{}' was lost, reconnecting", self.service, self.name);
+            let now = Instant::now();
+            *status = Status { state: State::Connecting, since: now, failures: 0, retry_at: now };
+        }
+    }
+
+    /// Exponential backoff with jitter, so proxies of the same backend do not retry all at once
+    fn backoff(&self, failures: u32) -> Duration {
+        let delay = INITIAL_BACKOFF.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(self.max_backoff);
+        rand::thread_rng().gen_range(delay / 2..=delay)
+    }
+
+    pub fn describe(&self) -> String {
+        let status = self.status.lock().unwrap();
+        let mut text = format!("{} for {}", status.state.describe(), format_duration(status.since.elapsed()));
+        if status.state == State::Disconnected {
+            text += &format!(", {} failed attempts, retrying in {}",
+                status.failures,
+                format_duration(status.retry_at.saturating_duration_since(Instant::now())));
+        }
+        text
+    }
+}
+
+/// Let the system probe an idle connection every `interval`, so a backend that went away
+/// without closing it is noticed
+pub fn set_keepalive(stream: &TcpStream, interval: Duration) {
+    if interval.is_zero() {
+        return;
+    }
+    let keepalive = TcpKeepalive::new().with_time(interval).with_interval(interval);
+    if let Err(err) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+        tracing::debug!("Failed to enable TCP keepalive: {err}");
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{:.1}s", duration.as_secs_f64()),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use notify::{RecursiveMode, Watcher};
+use rustls::{
+    InconsistentKeys,
+    RootCertStore,
+    pki_types::{CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier, danger::ClientCertVerifier},
+    sign::CertifiedKey
+};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use crate::config::{ClientAuthConfig, TlsConfig};
+
+/// Protocols offered with ALPN, HTTP/2 is preferred
+const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+pub fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|err| format!("could not open `{path}`: {err}"))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("could not read certificates from `{path}`: {err}"))?;
+    if certs.is_empty() {
+        return Err(format!("`{path}` does not contain any PEM certificate"));
+    }
+    Ok(certs)
+}
+
+pub fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|err| format!("could not open `{path}`: {err}"))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| format!("could not read the private key from `{path}`: {err}"))?
+        .ok_or_else(|| format!("`{path}` does not contain any PEM private key"))
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Load a certificate chain and its private key, the key must belong to the first certificate
+pub fn certified_key(cert: &str, key: &str) -> Result<Arc<CertifiedKey>, String> {
+    let certs = load_certs(cert)?;
+    let signing_key = provider().key_provider
+        .load_private_key(load_key(key)?)
+        .map_err(|err| format!("`{key}` is not a supported private key: {err}"))?;
+    let certified = CertifiedKey::new(certs, signing_key);
+    match certified.keys_match() {
+        // like rustls itself, accept keys that can not tell their public key
+        Ok(()) | Err(rustls::Error::InconsistentKeys(InconsistentKeys::Unknown)) => Ok(Arc::new(certified)),
+        Err(rustls::Error::InconsistentKeys(_)) => Err(format!("the private key in `{key}` does not match the certificate in `{cert}`")),
+        Err(err) => Err(format!("could not check the private key in `{key}` against the certificate in `{cert}`: {err}")),
+    }
+}
+
+/// The certificates of all server names, `*.` names are stored without their first label
+#[derive(Default)]
+struct Certs {
+    exact: HashMap<String, Arc<CertifiedKey>>,
+    wildcard: HashMap<String, Arc<CertifiedKey>>,
+    fallback: Option<Arc<CertifiedKey>>,
+}
+
+impl Certs {
+    fn load(config: &TlsConfig) -> Result<Self, String> {
+        let mut certs = Certs::default();
+        for entry in &config.certs {
+            let key = certified_key(&entry.cert, &entry.key)?;
+            for name in &entry.names {
+                let name = name.to_ascii_lowercase();
+                match name.strip_prefix("*.") {
+                    Some(domain) => certs.wildcard.insert(domain.to_string(), key.clone()),
+                    None => certs.exact.insert(name, key.clone()),
+                };
+            }
+            certs.fallback.get_or_insert(key);
+        }
+        // the top level certificate is for clients without a matching name
+        if let (Some(cert), Some(key)) = (&config.cert, &config.key) {
+            certs.fallback = Some(certified_key(cert, key)?);
+        }
+        Ok(certs)
+    }
+
+    fn get(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = server_name.map(str::to_ascii_lowercase) else {
+            return self.fallback.clone();
+        };
+        self.exact.get(&name)
+            .or_else(|| name.split_once('.').and_then(|(_, domain)| self.wildcard.get(domain)))
+            .or(self.fallback.as_ref())
+            .cloned()
+    }
+}
+
+/// Picks the certificate by the server name the client asks for (SNI),
+/// the certificates are replaced when their files change
+pub struct CertResolver {
+    certs: RwLock<Certs>,
+}
+
+impl std::fmt::Debug for CertResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertResolver").finish_non_exhaustive()
+    }
+}
+
+impl CertResolver {
+    pub fn load(config: &TlsConfig) -> Result<Self, String> {
+        Ok(Self { certs: RwLock::new(Certs::load(config)?) })
+    }
+
+    fn reload(&self, config: &TlsConfig) {
+        match Certs::load(config) {
+            Ok(certs) => {
+                *self.certs.write().unwrap() = certs;
+                tracing::info!("Reloaded TLS certificates");
+            }
+            // renewals often write the certificate and the key one after the other
+            Err(err) => tracing::warn!("Keeping the current TLS certificates, failed to reload them: {err}"),
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let cert = self.certs.read().unwrap().get(client_hello.server_name());
+        if cert.is_none() {
+            tracing::debug!("No TLS certificate for server name {:?}", client_hello.server_name());
+        }
+        cert
+    }
+}
+
+/// The verified certificate of a client, added to the extensions of its requests
+#[derive(Clone)]
+pub struct ClientCert {
+    /// Distinguished name of the certificate, like `CN=device-1, O=Example`
+    pub subject: String,
+}
+
+impl ClientCert {
+    pub fn from_der(cert: &CertificateDer) -> Option<Self> {
+        x509_parser::parse_x509_certificate(cert.as_ref()).ok()
+            .map(|(_, cert)| ClientCert { subject: cert.subject().to_string() })
+    }
+}
+
+/// Verifies client certificates against the CA bundle of `config`, clients without a
+/// certificate are only let through the handshake when some paths do not require one
+pub fn client_verifier(config: &ClientAuthConfig) -> Result<Arc<dyn ClientCertVerifier>, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&config.ca)? {
+        roots.add(cert).map_err(|err| format!("`{}` contains an invalid CA certificate: {err}", config.ca))?;
+    }
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider());
+    let builder = if config.paths.is_some() { builder.allow_unauthenticated() } else { builder };
+    builder.build().map_err(|err| format!("could not use `{}` to verify clients: {err}", config.ca))
+}
+
+/// The rustls config for the HTTPS listeners
+pub fn server_config(resolver: Arc<CertResolver>, client_auth: Option<&ClientAuthConfig>) -> Result<Arc<rustls::ServerConfig>, String> {
+    let builder = rustls::ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .expect("the default protocol versions are supported by the ring provider");
+    let builder = match client_auth {
+        Some(client_auth) => builder.with_client_cert_verifier(client_verifier(client_auth)?),
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(resolver);
+    server_config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(Arc::new(server_config))
+}
+
+/// Load the certificates of `config` and keep them up to date with their files
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let resolver = Arc::new(CertResolver::load(config)?);
+    let server_config = server_config(resolver.clone(), config.client_auth.as_ref())?;
+    spawn_cert_watcher(resolver, config.clone());
+    Ok(TlsAcceptor::from(server_config))
+}
+
+fn spawn_cert_watcher(resolver: Arc<CertResolver>, config: TlsConfig) {
+    // symlinks like the ones of certbot are watched both where they are and where they point to
+    let files = config.files().into_iter()
+        .flat_map(|file| [std::path::absolute(file).ok(), Path::new(file).canonicalize().ok()])
+        .flatten()
+        .collect::<Vec<_>>();
+    let watched = files.clone();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event {
+            if !event.kind.is_access() && event.paths.iter().any(|path| watched.contains(path)) {
+                let _ = tx.send(());
+            }
+        }
+    }) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+            tracing::error!("Failed to create TLS certificate watcher: {err}");
+            return;
+        }
+    };
+    // watch the directories, certificates are usually renewed by replacing the files
+    let mut directories = files.iter().filter_map(|file| file.parent()).collect::<Vec<_>>();
+    directories.sort();
+    directories.dedup();
+    for directory in directories {
+        if let Err(err) = watcher.watch(directory, RecursiveMode::NonRecursive) {
+            tracing::error!("Failed to watch {} for certificate changes: {err}", directory.display());
+        }
+    }
+
+    tokio::spawn(async move {
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            // wait until all files of a renewal are written
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            while rx.try_recv().is_ok() {}
+            resolver.reload(&config);
+        }
+    });
+}
@@ -1,16 +1,14 @@
-use std::sync::Arc;
-use std::time::Duration;
 use axum::{
     extract::Request,
-    http::StatusCode
-};
-use tokio::{
-    net::TcpStream,
-    sync::Mutex,
-    select
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header}
 };
+use tokio::net::TcpStream;
 use http_body_util::BodyExt;
-use tokio_tungstenite::{connect_async, WebSocketStream, MaybeTlsStream};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{Error as WsError, client::IntoClientRequest, handshake::client::Request as WsRequest},
+    WebSocketStream, MaybeTlsStream
+};
 use tracing_subscriber::{
     EnvFilter,
     layer::SubscriberExt,
@@ -19,7 +17,7 @@ use tracing_subscriber::{
 };
 use rustyline_async::SharedWriter;
 use tracing_appender::non_blocking::WorkerGuard;
-use crate::config::{ProxyConfig, SERVER_CONFIG};
+use crate::config::{SERVER_CONFIG, HandshakeConfig};
 
 pub fn banner() {
     println!(r#"
@@ -41,7 +39,7 @@ pub fn info() {
         "disabled"
     });
     if let Ok(rust_log) = std::env::var("RUST_LOG") {
-        tracing::warn!("RUST_LOG is set to `{}`, but gateserver will not use it. Please use the config file to set the log level.", rust_log);
+        tracing::warn!("RUST_LOG is set to `{}`, but gateserver will not use it. Please use the config file or --log-level to set the log level.", rust_log);
     }
 }
 
@@ -102,38 +100,58 @@ pub async fn get_body_from_request(mut req: Request) -> Result<Vec<u8>, StatusCo
     Ok(body_bytes)
 }
 
-pub async fn create_websocket_stream(uri: String) -> Option<WebSocketStream<MaybeTlsStream<TcpStream>>> {
-    match connect_async(uri.as_str()).await {
-        Ok((stream, _)) => Some(stream),
-        Err(err) => {
-            tracing::debug!("Creating Websocket connection error: {}", err);
-            None
+/// The request opening a WebSocket to `uri`, with the headers and subprotocols of `handshake`
+/// and its `forward_headers` copied from the client request `incoming`
+pub fn websocket_request(uri: &str, handshake: Option<&HandshakeConfig>, incoming: Option<&HeaderMap>) -> Result<WsRequest, Box<WsError>> {
+    let mut request = uri.into_client_request()?;
+    let Some(handshake) = handshake else {
+        return Ok(request);
+    };
+    let headers = request.headers_mut();
+    for (name, value) in &handshake.headers {
+        let name = HeaderName::try_from(name).map_err(invalid_header)?;
+        headers.insert(name, HeaderValue::try_from(value).map_err(invalid_header)?);
+    }
+    if !handshake.protocols.is_empty() {
+        // the client splits the offered protocols at commas only, without trimming them
+        let protocols = HeaderValue::try_from(handshake.protocols.join(",")).map_err(invalid_header)?;
+        headers.insert(header::SEC_WEBSOCKET_PROTOCOL, protocols);
+    }
+    let Some(incoming) = incoming else {
+        return Ok(request);
+    };
+    for name in &handshake.forward_headers {
+        let name = HeaderName::try_from(name).map_err(invalid_header)?;
+        // the header of the client replaces a static one with the same name
+        if incoming.contains_key(&name) {
+            headers.remove(&name);
+        }
+        for value in incoming.get_all(&name) {
+            headers.append(name.clone(), value.clone());
         }
     }
+    Ok(request)
+}
+
+fn invalid_header(err: impl Into<axum::http::Error>) -> WsError {
+    WsError::from(err.into())
 }
 
-pub async fn make_websocket_stream(config: &ProxyConfig) -> Option<Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>> {
-    let mut ws_proxy = None;
-    for tried_num in 0..3 {
-        ws_proxy = select! {
-                Some(stream) = create_websocket_stream(config.forward_to.clone()) => {
-                    Some(Arc::new(Mutex::new(stream)))
-                },
-                _ = tokio::time::sleep(Duration::from_millis(2000)) => {
-                    tracing::warn!("Failed to connect with Websocket server '{}', remaining attempts: {}",
-                        config.forward_to,
-                        2 - tried_num);
-                    if tried_num < 2 { continue; }
-                    tracing::error!("Failed to connect with Websocket server '{}', Websocket proxy not working",
-                        config.forward_to);
-                    None
-                }
-            };
-        if ws_proxy.is_some() {
-            break;
+pub async fn create_websocket_stream(uri: String, handshake: Option<HandshakeConfig>) -> Option<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let request = match websocket_request(&uri, handshake.as_ref(), None) {
+        Ok(request) => request,
+        Err(err) => {
+            tracing::debug!("Creating Websocket connection error: {}", err);
+            return None;
+        }
+    };
+    match connect_async(request).await {
+        Ok((stream, _)) => Some(stream),
+        Err(err) => {
+            tracing::debug!("Creating Websocket connection error: {}", err);
+            None
         }
     }
-    ws_proxy
 }
 
 pub async fn create_tcp_stream(uri: String) -> Option<TcpStream> {
@@ -146,30 +164,6 @@ pub async fn create_tcp_stream(uri: String) -> Option<TcpStream> {
     }
 }
 
-pub async fn make_tcp_stream(config: &ProxyConfig) -> Option<Arc<Mutex<TcpStream>>> {
-    let mut tcp_proxy = None;
-    for tried_num in 0..3 {
-        tcp_proxy = select! {
-                Some(stream) = create_tcp_stream(config.forward_to.clone()) => {
-                    Some(Arc::new(Mutex::new(stream)))
-                },
-                _ = tokio::time::sleep(Duration::from_millis(2000)) => {
-                    tracing::warn!("Failed to connect with TCP server '{}', remaining attempts: {}",
-                        config.forward_to,
-                        2 - tried_num);
-                    if tried_num < 2 { continue; }
-                    tracing::error!("Failed to connect with TCP server '{}', TCP proxy not working",
-                        config.forward_to);
-                    None
-                }
-            };
-        if tcp_proxy.is_some() {
-            break;
-        }
-    }
-    tcp_proxy
-}
-
 pub fn debug_print_bytes(bytes: &Vec<u8>, source: &str) {
     if let Ok(msg) = String::from_utf8(bytes.clone()) {
         tracing::debug!("Received message from {} ({} bytes): {}",
@@ -184,7 +178,6 @@ pub fn debug_print_bytes(bytes: &Vec<u8>, source: &str) {
 }
 
 pub fn wait_file_log_guard(guard: WorkerGuard) {
-    tracing::warn!("Fail to create Readline, console log with std::io::Stdout...");
     tokio::spawn(async {
         if let Err(err) = tokio::signal::ctrl_c().await {
             tracing::error!("Failed to listen for Ctrl+C, log files maybe incomplete: {}", err);